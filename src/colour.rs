@@ -1,11 +1,269 @@
-/// Colour palette generation for Mandelbrot visualisation
+//! Colour palette generation for Mandelbrot visualisation
 
 use serde::{Deserialize, Serialize};
+use std::f64::consts::TAU;
+use std::sync::OnceLock;
 
-/// Available colour palettes
+/// sRGB-encoded colour with channels nominally in `0.0..=1.0`
+///
+/// Colours stay in floating point through palettes, lighting and
+/// colouring; they are only quantised when written out in a `PixelFormat`.
+pub type Rgb = [f32; 3];
+
+/// How rendered pixels are stored in strips and assembled frames
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "snake_case")]
+pub enum PixelFormat {
+    /// 8 bits per sRGB channel
+    #[default]
+    Rgb8,
+    /// 16 bits per sRGB channel, little-endian
+    Rgb16,
+    /// Linear-light f32 per channel, little-endian, for tone mapping
+    RgbF32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb8 => 3,
+            PixelFormat::Rgb16 => 6,
+            PixelFormat::RgbF32 => 12,
+        }
+    }
+
+    /// Write one pixel into `out`, which is `bytes_per_pixel` long
+    #[inline]
+    pub fn write(self, colour: Rgb, out: &mut [u8]) {
+        self.write_dithered(colour, 0.0, out);
+    }
+
+    /// Write one pixel, offset by `threshold` quantisation steps before
+    /// rounding (see `Dither::threshold`). Float pixels aren't quantised.
+    #[inline]
+    pub fn write_dithered(self, colour: Rgb, threshold: f32, out: &mut [u8]) {
+        match self {
+            PixelFormat::Rgb8 => {
+                for (byte, c) in out.iter_mut().zip(colour) {
+                    *byte = (c.clamp(0.0, 1.0) * 255.0 + threshold).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+            PixelFormat::Rgb16 => {
+                for (bytes, c) in out.chunks_exact_mut(2).zip(colour) {
+                    let value = (c.clamp(0.0, 1.0) * 65535.0 + threshold).round().clamp(0.0, 65535.0) as u16;
+                    bytes.copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            PixelFormat::RgbF32 => {
+                for (bytes, c) in out.chunks_exact_mut(4).zip(colour) {
+                    let value = srgb_to_linear(c.max(0.0) as f64) as f32;
+                    bytes.copy_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Noise added when quantising, to break up banding in smooth gradients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Dither {
+    #[default]
+    None,
+    /// 8x8 ordered Bayer matrix
+    Bayer,
+    /// 64x64 void-and-cluster blue-noise mask
+    BlueNoise,
+}
+
+/// Side of the tiled blue-noise mask
+const BLUE_NOISE_SIZE: usize = 64;
+
+impl Dither {
+    /// Offset for frame pixel `(px, py)`, in quantisation steps within `-0.5..0.5`
+    ///
+    /// It depends only on where the pixel is in the frame, so tiles rendered
+    /// by different workers join without seams.
+    #[inline]
+    pub fn threshold(self, px: u32, py: u32) -> f32 {
+        match self {
+            Dither::None => 0.0,
+            Dither::Bayer => (bayer_rank(px % 8, py % 8) as f32 + 0.5) / 64.0 - 0.5,
+            Dither::BlueNoise => {
+                let n = BLUE_NOISE_SIZE;
+                let rank = blue_noise_mask()[(py as usize % n) * n + px as usize % n];
+                (rank as f32 + 0.5) / (n * n) as f32 - 0.5
+            }
+        }
+    }
+}
+
+/// Rank of a cell in the 8x8 Bayer matrix: the bits of `x ^ y` and `y`
+/// interleaved, most significant last
+#[inline]
+fn bayer_rank(x: u32, y: u32) -> u32 {
+    (0..3).fold(0, |rank, bit| {
+        let shift = 2 * (2 - bit);
+        rank | (((x ^ y) >> bit) & 1) << (shift + 1) | ((y >> bit) & 1) << shift
+    })
+}
+
+/// Rank of each cell of the blue-noise mask, generated on first use
+fn blue_noise_mask() -> &'static [u16] {
+    static MASK: OnceLock<Vec<u16>> = OnceLock::new();
+    MASK.get_or_init(generate_blue_noise)
+}
+
+/// Binary pattern on the torus with the Gaussian energy each cell receives
+/// from the set cells, for void-and-cluster
+struct NoisePattern {
+    set: Vec<bool>,
+    energy: Vec<f32>,
+}
+
+impl NoisePattern {
+    fn toggle(&mut self, cell: usize, kernel: &[f32]) {
+        let n = BLUE_NOISE_SIZE;
+        self.set[cell] = !self.set[cell];
+        let sign = if self.set[cell] { 1.0 } else { -1.0 };
+        let (cx, cy) = (cell % n, cell / n);
+        for (i, energy) in self.energy.iter_mut().enumerate() {
+            let (dx, dy) = ((i % n + n - cx) % n, (i / n + n - cy) % n);
+            *energy += sign * kernel[dy * n + dx];
+        }
+    }
+
+    /// Set cell with the most energy
+    fn tightest_cluster(&self) -> usize {
+        (0..self.set.len())
+            .filter(|&i| self.set[i])
+            .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+
+    /// Unset cell with the least energy
+    fn largest_void(&self) -> usize {
+        (0..self.set.len())
+            .filter(|&i| !self.set[i])
+            .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+}
+
+/// Ulichney's void-and-cluster method, from a fixed seed so every worker
+/// builds the same mask
+fn generate_blue_noise() -> Vec<u16> {
+    let n = BLUE_NOISE_SIZE;
+    let sigma = 1.5f32;
+    let kernel: Vec<f32> = (0..n * n)
+        .map(|i| {
+            let dx = (i % n).min(n - i % n) as f32;
+            let dy = (i / n).min(n - i / n) as f32;
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+
+    // Sparse initial pattern from xorshift
+    let mut pattern = NoisePattern { set: vec![false; n * n], energy: vec![0.0; n * n] };
+    let initial = n * n / 10;
+    let mut state = 0x2545_f491u32;
+    let mut count = 0;
+    while count < initial {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let cell = state as usize % (n * n);
+        if !pattern.set[cell] {
+            pattern.toggle(cell, &kernel);
+            count += 1;
+        }
+    }
+
+    // Spread it out: move the tightest cluster into the largest void until
+    // that changes nothing
+    loop {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster, &kernel);
+        let void = pattern.largest_void();
+        pattern.toggle(void, &kernel);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // Rank the initial points by removing clusters, then the rest by filling voids
+    let mut rank = vec![0u16; n * n];
+    let mut thinning = NoisePattern { set: pattern.set.clone(), energy: pattern.energy.clone() };
+    for r in (0..initial).rev() {
+        let cluster = thinning.tightest_cluster();
+        thinning.toggle(cluster, &kernel);
+        rank[cluster] = r as u16;
+    }
+    for r in initial..n * n {
+        let void = pattern.largest_void();
+        pattern.toggle(void, &kernel);
+        rank[void] = r as u16;
+    }
+    rank
+}
+
+/// Colour palette for a frame: a named preset or a custom gradient
+///
+/// Presets and imported palettes are given by name (`"fire"`), gradients as
+/// an object with a list of control points.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
 pub enum Palette {
+    Preset(Preset),
+    /// Imported palette, resolved by the coordinator before rendering
+    Named(String),
+    Gradient(Gradient),
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::Preset(Preset::default())
+    }
+}
+
+/// Entries in the palette tables used for rendering
+pub const PALETTE_SIZE: usize = 2048;
+
+impl Palette {
+    /// Stable hash of the palette definition
+    ///
+    /// FNV-1a over the serialised definition, so coordinator and workers
+    /// agree on it whatever build they are running.
+    pub fn hash_key(&self) -> u64 {
+        let definition = serde_json::to_string(self).unwrap_or_default();
+        definition.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    pub fn generate(&self, num_colours: usize) -> Vec<Rgb> {
+        match self {
+            Palette::Preset(preset) => preset.generate(num_colours),
+            Palette::Gradient(gradient) => gradient.generate(num_colours),
+            Palette::Named(name) => {
+                tracing::warn!("Unresolved palette '{}', using the default", name);
+                Preset::default().generate(num_colours)
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Palette::Preset(_) | Palette::Named(_) => Ok(()),
+            Palette::Gradient(gradient) => gradient.validate(),
+        }
+    }
+}
+
+/// Built-in palettes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
     #[default]
     Fire,
     Ocean,
@@ -15,36 +273,480 @@ pub enum Palette {
     Twilight,
     Forest,
     Lava,
+    /// Perceptually uniform and colourblind-safe, purple to yellow and back
+    Viridis,
+    /// Blue to yellow and back, near-identical under colour vision deficiencies
+    Cividis,
 }
 
-impl Palette {
-    pub fn generate(&self, num_colours: usize) -> Vec<(u8, u8, u8)> {
+impl Preset {
+    /// Sample the preset's generator directly at `num_colours` positions
+    pub fn generate(&self, num_colours: usize) -> Vec<Rgb> {
+        self.colours(num_colours)
+            .into_iter()
+            .map(|(r, g, b)| [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0])
+            .collect()
+    }
+
+    fn colours(&self, num_colours: usize) -> Vec<(u8, u8, u8)> {
         match self {
-            Palette::Fire => generate_fire_palette(num_colours),
-            Palette::Ocean => generate_ocean_palette(num_colours),
-            Palette::Electric => generate_electric_palette(num_colours),
-            Palette::Monochrome => generate_monochrome_palette(num_colours),
-            Palette::Rainbow => generate_rainbow_palette(num_colours),
-            Palette::Twilight => generate_twilight_palette(num_colours),
-            Palette::Forest => generate_forest_palette(num_colours),
-            Palette::Lava => generate_lava_palette(num_colours),
+            Preset::Fire => generate_fire_palette(num_colours),
+            Preset::Ocean => generate_ocean_palette(num_colours),
+            Preset::Electric => generate_electric_palette(num_colours),
+            Preset::Monochrome => generate_monochrome_palette(num_colours),
+            Preset::Rainbow => generate_rainbow_palette(num_colours),
+            Preset::Twilight => generate_twilight_palette(num_colours),
+            Preset::Forest => generate_forest_palette(num_colours),
+            Preset::Lava => generate_lava_palette(num_colours),
+            Preset::Viridis => generate_sequential_palette(num_colours, &VIRIDIS),
+            Preset::Cividis => generate_sequential_palette(num_colours, &CIVIDIS),
         }
     }
 
-    pub fn all() -> &'static [Palette] {
+    #[cfg(test)]
+    pub fn all() -> &'static [Preset] {
         &[
-            Palette::Fire,
-            Palette::Ocean,
-            Palette::Electric,
-            Palette::Monochrome,
-            Palette::Rainbow,
-            Palette::Twilight,
-            Palette::Forest,
-            Palette::Lava,
+            Preset::Fire,
+            Preset::Ocean,
+            Preset::Electric,
+            Preset::Monochrome,
+            Preset::Rainbow,
+            Preset::Twilight,
+            Preset::Forest,
+            Preset::Lava,
+            Preset::Viridis,
+            Preset::Cividis,
         ]
     }
 }
 
+/// How colours are blended between neighbouring control points
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    /// Eased in and out of each control point
+    Smoothstep,
+    /// Hold each colour until the next control point
+    Step,
+}
+
+/// Colour space for blending between control points
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ColourSpace {
+    /// Blend the gamma-encoded bytes directly
+    #[default]
+    Srgb,
+    /// Blend light intensities; brighter, less muddy midpoints
+    LinearRgb,
+    /// Perceptually uniform lightness and hue
+    Oklab,
+    /// OKLab in polar form, blending hue round the shorter way
+    Oklch,
+}
+
+impl ColourSpace {
+    /// Blend from `a` to `b` by `t` in this colour space
+    pub fn mix(self, a: [u8; 3], b: [u8; 3], t: f64) -> Rgb {
+        let (a, b) = (self.encode(a), self.encode(b));
+        let mut blended = [0, 1, 2].map(|c| a[c] + (b[c] - a[c]) * t);
+        if self == ColourSpace::Oklch {
+            // Hue is an angle; an achromatic end takes the other end's hue
+            let (mut ha, mut hb) = (a[2], b[2]);
+            if a[1] < 1e-6 {
+                ha = hb;
+            } else if b[1] < 1e-6 {
+                hb = ha;
+            }
+            let delta = (hb - ha + std::f64::consts::PI).rem_euclid(TAU) - std::f64::consts::PI;
+            blended[2] = ha + delta * t;
+        }
+        self.decode(blended)
+    }
+
+    /// Coordinates of an sRGB colour in this space
+    pub fn encode(self, colour: [u8; 3]) -> [f64; 3] {
+        let linear = colour.map(|c| srgb_to_linear(c as f64 / 255.0));
+        match self {
+            ColourSpace::Srgb => colour.map(|c| c as f64 / 255.0),
+            ColourSpace::LinearRgb => linear,
+            ColourSpace::Oklab => linear_to_oklab(linear),
+            ColourSpace::Oklch => {
+                let [l, a, b] = linear_to_oklab(linear);
+                [l, a.hypot(b), b.atan2(a)]
+            }
+        }
+    }
+
+    /// sRGB colour at coordinates in this space, clipped to the gamut
+    pub fn decode(self, value: [f64; 3]) -> Rgb {
+        let linear = match self {
+            ColourSpace::Srgb => return value.map(|c| c.clamp(0.0, 1.0) as f32),
+            ColourSpace::LinearRgb => value,
+            ColourSpace::Oklab => oklab_to_linear(value),
+            ColourSpace::Oklch => {
+                let [l, c, h] = value;
+                oklab_to_linear([l, c * h.cos(), c * h.sin()])
+            }
+        };
+        linear.map(|c| linear_to_srgb(c.clamp(0.0, 1.0)) as f32)
+    }
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Linear sRGB to OKLab (Björn Ottosson's reference matrices)
+fn linear_to_oklab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_linear([l, a, b]: [f64; 3]) -> [f64; 3] {
+    let l_ = (l + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m_ = (l - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s_ = (l - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l_ - 3.3077115913 * m_ + 0.2309699292 * s_,
+        -1.2684380046 * l_ + 2.6097574011 * m_ - 0.3413193965 * s_,
+        -0.0041960863 * l_ - 0.7034186147 * m_ + 1.7076147010 * s_,
+    ]
+}
+
+/// A colour at a position along a gradient
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ColourStop {
+    /// Position in `0.0..=1.0`
+    pub position: f64,
+    pub colour: [u8; 3],
+}
+
+/// Gradient palette defined by control points
+///
+/// The palette is cyclic like the presets: past the last control point it
+/// blends back round to the first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Gradient {
+    pub stops: Vec<ColourStop>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    /// Colour space the blends between control points are made in
+    #[serde(default)]
+    pub space: ColourSpace,
+}
+
+/// Most control points accepted in a gradient
+const MAX_STOPS: usize = 1024;
+
+impl Gradient {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.stops.is_empty() {
+            return Err("Gradient needs at least one colour stop".to_string());
+        }
+        if self.stops.len() > MAX_STOPS {
+            return Err(format!("Gradient has more than {} colour stops", MAX_STOPS));
+        }
+        if let Some(stop) = self.stops.iter().find(|s| !(0.0..=1.0).contains(&s.position)) {
+            return Err(format!("Colour stop position {} is outside 0..1", stop.position));
+        }
+        Ok(())
+    }
+
+    /// Sample the gradient at `num_colours` evenly spaced positions
+    pub fn generate(&self, num_colours: usize) -> Vec<Rgb> {
+        let mut stops = self.stops.clone();
+        stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+        if stops.is_empty() {
+            return vec![[0.0; 3]; num_colours];
+        }
+
+        let first = stops[0];
+        let last = stops[stops.len() - 1];
+        (0..num_colours)
+            .map(|i| {
+                let t = i as f64 / num_colours as f64;
+                let next = stops.partition_point(|s| s.position <= t);
+                // Before the first or after the last stop, wrap round
+                let (from, to, start, end) = match next {
+                    0 => (last, first, last.position - 1.0, first.position),
+                    n if n == stops.len() => (last, first, last.position, first.position + 1.0),
+                    n => (stops[n - 1], stops[n], stops[n - 1].position, stops[n].position),
+                };
+                let frac = if end > start { (t - start) / (end - start) } else { 0.0 };
+                let frac = match self.interpolation {
+                    Interpolation::Linear => frac,
+                    Interpolation::Smoothstep => frac * frac * (3.0 - 2.0 * frac),
+                    Interpolation::Step => 0.0,
+                };
+                self.space.mix(from.colour, to.colour, frac)
+            })
+            .collect()
+    }
+}
+
+/// Parse a palette file from another program, chosen by file extension
+///
+/// Returns each gradient in the file with its name. Fractint `.map` and GIMP
+/// `.ggr` files hold one gradient; UltraFractal `.ugr` files can hold many.
+pub fn import_palettes(file_name: &str, text: &str) -> Result<Vec<(String, Gradient)>, String> {
+    let path = std::path::Path::new(file_name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(file_name).to_string();
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+
+    match extension.as_str() {
+        "map" => Ok(vec![(stem, parse_map(text)?)]),
+        "ggr" => {
+            let (name, gradient) = parse_ggr(text)?;
+            Ok(vec![(name.unwrap_or(stem), gradient)])
+        }
+        "ugr" => parse_ugr(text),
+        _ => Err(format!("Unsupported palette file '{}'; expected .map, .ggr or .ugr", file_name)),
+    }
+}
+
+/// Parse a Fractint `.map` file: one `r g b` entry per line, evenly spaced
+///
+/// Anything after the three numbers on a line is a comment.
+pub fn parse_map(text: &str) -> Result<Gradient, String> {
+    let mut colours = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().take(3).collect();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() < 3 {
+            return Err(format!("Line {}: expected three colour values", number + 1));
+        }
+
+        let mut colour = [0u8; 3];
+        for (value, field) in colour.iter_mut().zip(&fields) {
+            *value = field
+                .parse()
+                .map_err(|_| format!("Line {}: expected a colour value 0-255, got '{}'", number + 1, field))?;
+        }
+        colours.push(colour);
+    }
+
+    if colours.is_empty() {
+        return Err("Map file has no colours".to_string());
+    }
+    let count = colours.len() as f64;
+    Ok(Gradient {
+        stops: colours
+            .into_iter()
+            .enumerate()
+            .map(|(i, colour)| ColourStop { position: i as f64 / count, colour })
+            .collect(),
+        interpolation: Interpolation::Linear,
+        space: ColourSpace::Srgb,
+    })
+}
+
+/// Control points sampled from each curved GIMP gradient segment
+const GGR_SAMPLES: usize = 8;
+
+/// Parse a GIMP `.ggr` gradient, returning its name if it has one
+///
+/// GIMP segments each have their own blend function and midpoint, so
+/// segments that aren't plain linear blends are sampled into several
+/// control points. Opacity is ignored.
+pub fn parse_ggr(text: &str) -> Result<(Option<String>, Gradient), String> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("GIMP Gradient") {
+        return Err("Not a GIMP gradient: missing 'GIMP Gradient' header".to_string());
+    }
+
+    let mut line = lines.next().ok_or("GIMP gradient is truncated")?;
+    let mut name = None;
+    if let Some(rest) = line.strip_prefix("Name:") {
+        name = Some(rest.trim().to_string());
+        line = lines.next().ok_or("GIMP gradient is truncated")?;
+    }
+    let count: usize = line.parse().map_err(|_| format!("Expected segment count, got '{}'", line))?;
+
+    let mut stops = Vec::new();
+    for segment in 0..count {
+        let line = lines.next().ok_or("GIMP gradient has fewer segments than declared")?;
+        let values: Vec<f64> = line
+            .split_whitespace()
+            .map(|v| v.parse().map_err(|_| format!("Segment {}: bad number '{}'", segment + 1, v)))
+            .collect::<Result<_, _>>()?;
+        if values.len() < 13 {
+            return Err(format!("Segment {}: expected at least 13 values", segment + 1));
+        }
+
+        let (left, middle, right) = (values[0], values[1], values[2]);
+        let from = [values[3], values[4], values[5]];
+        let to = [values[7], values[8], values[9]];
+        let blend = values[11] as u32;
+        let width = right - left;
+        let midpoint = if width > 0.0 { ((middle - left) / width).clamp(1e-6, 1.0 - 1e-6) } else { 0.5 };
+
+        let samples = if blend == 0 && (midpoint - 0.5).abs() < 1e-6 { 1 } else { GGR_SAMPLES };
+        for i in 0..samples {
+            let pos = i as f64 / samples as f64;
+            let factor = ggr_blend(blend, pos, midpoint);
+            stops.push(ColourStop {
+                position: (left + pos * width).clamp(0.0, 1.0),
+                colour: [0, 1, 2].map(|c| unit_to_byte(from[c] + (to[c] - from[c]) * factor)),
+            });
+        }
+        // Close the final segment so the gradient ends on its right colour
+        if segment + 1 == count && right < 1.0 + 1e-9 {
+            stops.push(ColourStop {
+                position: right.clamp(0.0, 1.0),
+                colour: to.map(unit_to_byte),
+            });
+        }
+    }
+
+    if stops.is_empty() {
+        return Err("GIMP gradient has no segments".to_string());
+    }
+    let gradient = Gradient {
+        stops,
+        interpolation: Interpolation::Linear,
+        space: ColourSpace::Srgb,
+    };
+    Ok((name, gradient))
+}
+
+fn unit_to_byte(value: f64) -> u8 {
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// GIMP blend functions: how far from the left colour to the right at `pos`
+fn ggr_blend(blend: u32, pos: f64, midpoint: f64) -> f64 {
+    // Piecewise linear through (midpoint, 0.5)
+    let linear = if pos <= midpoint {
+        0.5 * pos / midpoint
+    } else {
+        0.5 + 0.5 * (pos - midpoint) / (1.0 - midpoint)
+    };
+    match blend {
+        1 => pos.powf(0.5f64.ln() / midpoint.ln()),
+        2 => ((-std::f64::consts::FRAC_PI_2 + std::f64::consts::PI * linear).sin() + 1.0) / 2.0,
+        3 => (1.0 - (linear - 1.0) * (linear - 1.0)).sqrt(),
+        4 => 1.0 - (1.0 - linear * linear).sqrt(),
+        5 => if pos >= midpoint { 1.0 } else { 0.0 },
+        _ => linear,
+    }
+}
+
+/// Positions in an UltraFractal gradient
+const UGR_INDICES: f64 = 400.0;
+
+/// Parse an UltraFractal `.ugr` collection into named gradients
+///
+/// Each entry is `name { gradient: title="..." smooth=yes index=N color=C ... }`
+/// with colours as decimal `0xBBGGRR`. Opacity sections are ignored.
+pub fn parse_ugr(text: &str) -> Result<Vec<(String, Gradient)>, String> {
+    let mut gradients = Vec::new();
+    let mut current: Option<(String, Gradient)> = None;
+    let mut index: Option<i64> = None;
+    let mut in_opacity = false;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(entry) = line.strip_suffix('{') {
+            let gradient = Gradient {
+                stops: Vec::new(),
+                interpolation: Interpolation::Linear,
+                space: ColourSpace::Srgb,
+            };
+            current = Some((entry.trim().to_string(), gradient));
+            in_opacity = false;
+            continue;
+        }
+        if line == "}" {
+            if let Some((name, gradient)) = current.take() {
+                if gradient.stops.is_empty() {
+                    return Err(format!("Gradient '{}' has no colours", name));
+                }
+                gradients.push((name, gradient));
+            }
+            continue;
+        }
+        let Some((name, gradient)) = current.as_mut() else { continue };
+        match line {
+            "gradient:" => in_opacity = false,
+            "opacity:" => in_opacity = true,
+            _ if in_opacity => {}
+            _ => {
+                for (key, value) in ugr_fields(line) {
+                    let bad = || format!("Line {}: bad {} '{}'", number + 1, key, value);
+                    match key {
+                        "title" => *name = value.to_string(),
+                        "smooth" if value == "yes" => gradient.interpolation = Interpolation::Smoothstep,
+                        "index" => index = Some(value.parse().map_err(|_| bad())?),
+                        "color" => {
+                            let colour: u32 = value.parse().map_err(|_| bad())?;
+                            let position = index.take().ok_or_else(bad)? as f64;
+                            gradient.stops.push(ColourStop {
+                                position: position.rem_euclid(UGR_INDICES) / UGR_INDICES,
+                                colour: [colour as u8, (colour >> 8) as u8, (colour >> 16) as u8],
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    if current.is_some() {
+        return Err("UltraFractal gradient is missing a closing '}'".to_string());
+    }
+    if gradients.is_empty() {
+        return Err("No gradients found".to_string());
+    }
+    Ok(gradients)
+}
+
+/// Split a line of `key=value key="quoted value"` pairs
+fn ugr_fields(line: &str) -> Vec<(&str, &str)> {
+    let mut fields = Vec::new();
+    let mut rest = line.trim_start();
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        let after = &rest[eq + 1..];
+        let (value, remainder) = match after.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => {
+                let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        fields.push((key, value));
+        rest = remainder.trim_start();
+    }
+    fields
+}
+
 /// Classic fire palette - reds, oranges, yellows
 fn generate_fire_palette(num_colours: usize) -> Vec<(u8, u8, u8)> {
     let mut palette = Vec::with_capacity(num_colours);
@@ -52,9 +754,9 @@ fn generate_fire_palette(num_colours: usize) -> Vec<(u8, u8, u8)> {
     for i in 0..num_colours {
         let t = i as f64 / num_colours as f64;
 
-        let r = (0.5 + 0.5 * (3.0 + t * 6.28318 + 0.0).sin()) * 255.0;
-        let g = (0.5 + 0.5 * (3.0 + t * 6.28318 + 2.094).sin()) * 255.0;
-        let b = (0.5 + 0.5 * (3.0 + t * 6.28318 + 4.188).sin()) * 255.0;
+        let r = (0.5 + 0.5 * (3.0 + t * TAU + 0.0).sin()) * 255.0;
+        let g = (0.5 + 0.5 * (3.0 + t * TAU + 2.094).sin()) * 255.0;
+        let b = (0.5 + 0.5 * (3.0 + t * TAU + 4.188).sin()) * 255.0;
 
         palette.push((r as u8, g as u8, b as u8));
     }
@@ -69,9 +771,9 @@ fn generate_ocean_palette(num_colours: usize) -> Vec<(u8, u8, u8)> {
     for i in 0..num_colours {
         let t = i as f64 / num_colours as f64;
 
-        let r = (0.1 + 0.2 * (t * 6.28318 + 4.0).sin()) * 255.0;
-        let g = (0.3 + 0.4 * (t * 6.28318 + 2.0).sin()) * 255.0;
-        let b = (0.5 + 0.5 * (t * 6.28318).sin()) * 255.0;
+        let r = (0.1 + 0.2 * (t * TAU + 4.0).sin()) * 255.0;
+        let g = (0.3 + 0.4 * (t * TAU + 2.0).sin()) * 255.0;
+        let b = (0.5 + 0.5 * (t * TAU).sin()) * 255.0;
 
         palette.push((
             r.clamp(0.0, 255.0) as u8,
@@ -144,9 +846,9 @@ fn generate_twilight_palette(num_colours: usize) -> Vec<(u8, u8, u8)> {
     for i in 0..num_colours {
         let t = i as f64 / num_colours as f64;
 
-        let r = (0.4 + 0.4 * (t * 6.28318 * 2.0).sin()) * 255.0;
-        let g = (0.1 + 0.15 * (t * 6.28318 * 3.0 + 1.0).sin()) * 255.0;
-        let b = (0.5 + 0.5 * (t * 6.28318 + 0.5).sin()) * 255.0;
+        let r = (0.4 + 0.4 * (t * TAU * 2.0).sin()) * 255.0;
+        let g = (0.1 + 0.15 * (t * TAU * 3.0 + 1.0).sin()) * 255.0;
+        let b = (0.5 + 0.5 * (t * TAU + 0.5).sin()) * 255.0;
 
         palette.push((
             r.clamp(0.0, 255.0) as u8,
@@ -165,9 +867,9 @@ fn generate_forest_palette(num_colours: usize) -> Vec<(u8, u8, u8)> {
     for i in 0..num_colours {
         let t = i as f64 / num_colours as f64;
 
-        let r = (0.3 + 0.25 * (t * 6.28318 * 2.0 + 2.0).sin()) * 255.0;
-        let g = (0.4 + 0.4 * (t * 6.28318).sin()) * 255.0;
-        let b = (0.15 + 0.15 * (t * 6.28318 * 1.5 + 1.0).sin()) * 255.0;
+        let r = (0.3 + 0.25 * (t * TAU * 2.0 + 2.0).sin()) * 255.0;
+        let g = (0.4 + 0.4 * (t * TAU).sin()) * 255.0;
+        let b = (0.15 + 0.15 * (t * TAU * 1.5 + 1.0).sin()) * 255.0;
 
         palette.push((
             r.clamp(0.0, 255.0) as u8,
@@ -187,7 +889,7 @@ fn generate_lava_palette(num_colours: usize) -> Vec<(u8, u8, u8)> {
         let t = i as f64 / num_colours as f64;
 
         // Emphasise reds and oranges with dark bands
-        let intensity = (t * 6.28318 * 3.0).sin().powi(2);
+        let intensity = (t * TAU * 3.0).sin().powi(2);
         let r = (intensity * 255.0) as u8;
         let g = ((intensity * 0.5).powf(1.5) * 255.0) as u8;
         let b = ((intensity * 0.2).powf(2.0) * 255.0) as u8;
@@ -198,6 +900,120 @@ fn generate_lava_palette(num_colours: usize) -> Vec<(u8, u8, u8)> {
     palette
 }
 
+/// Viridis, sampled evenly from dark to light
+const VIRIDIS: [(u8, u8, u8); 9] = [
+    (68, 1, 84),
+    (72, 40, 120),
+    (62, 73, 137),
+    (49, 104, 142),
+    (38, 130, 142),
+    (31, 158, 137),
+    (53, 183, 121),
+    (110, 206, 88),
+    (253, 231, 37),
+];
+
+/// Cividis, sampled evenly from dark to light
+const CIVIDIS: [(u8, u8, u8); 9] = [
+    (0, 32, 77),
+    (0, 51, 111),
+    (57, 72, 107),
+    (87, 92, 109),
+    (112, 113, 115),
+    (138, 135, 121),
+    (166, 157, 117),
+    (211, 193, 100),
+    (255, 234, 70),
+];
+
+/// Run a sequential colour map up and back down, so the cycle has no seam
+fn generate_sequential_palette(num_colours: usize, anchors: &[(u8, u8, u8)]) -> Vec<(u8, u8, u8)> {
+    let last = (anchors.len() - 1) as f64;
+    (0..num_colours)
+        .map(|i| {
+            let t = i as f64 / num_colours as f64;
+            let position = (1.0 - (2.0 * t - 1.0).abs()) * last;
+            let idx = (position.floor() as usize).min(anchors.len() - 2);
+            let frac = position - idx as f64;
+            let (from, to) = (anchors[idx], anchors[idx + 1]);
+            let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * frac).round() as u8;
+            (mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+        })
+        .collect()
+}
+
+/// Shading model for 3D lighting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LightingModel {
+    /// Diffuse only
+    #[default]
+    Lambert,
+    /// Diffuse plus a specular highlight
+    BlinnPhong,
+}
+
+/// Directional light used to shade the exterior as an embossed surface
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Lighting {
+    /// Light direction in the image plane, in degrees anticlockwise from +x
+    #[serde(default = "default_light_angle")]
+    pub angle: f64,
+    /// Height of the light above the surface; higher flattens the relief
+    #[serde(default = "default_light_height")]
+    pub height: f64,
+    #[serde(default)]
+    pub model: LightingModel,
+    /// Specular exponent for Blinn-Phong
+    #[serde(default = "default_shininess")]
+    pub shininess: f64,
+}
+
+fn default_light_angle() -> f64 {
+    45.0
+}
+
+fn default_light_height() -> f64 {
+    1.5
+}
+
+fn default_shininess() -> f64 {
+    32.0
+}
+
+impl Lighting {
+    /// Share of the palette colour that is kept in full shadow
+    const AMBIENT: f64 = 0.25;
+
+    /// Shade a palette colour given the unit surface normal `(nx, ny)`
+    pub fn shade(&self, colour: Rgb, nx: f64, ny: f64) -> Rgb {
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let height = self.height.max(0.0);
+
+        // Classic slope shading: project the normal onto the light direction
+        let diffuse = ((nx * cos + ny * sin + height) / (1.0 + height)).max(0.0);
+        let mut brightness = Self::AMBIENT + (1.0 - Self::AMBIENT) * diffuse;
+
+        let mut specular = 0.0;
+        if self.model == LightingModel::BlinnPhong {
+            // Surface normal (nx, ny, 1), light (cos, sin, height), viewer (0, 0, 1)
+            let normal = normalise3(nx, ny, 1.0);
+            let light = normalise3(cos, sin, height);
+            let half = normalise3(light.0, light.1, light.2 + 1.0);
+            let n_dot_h = normal.0 * half.0 + normal.1 * half.1 + normal.2 * half.2;
+            specular = n_dot_h.max(0.0).powf(self.shininess.max(1.0));
+            brightness = brightness.min(1.0);
+        }
+
+        colour.map(|c| (c as f64 * brightness + specular).clamp(0.0, 1.0) as f32)
+    }
+}
+
+fn normalise3(x: f64, y: f64, z: f64) -> (f64, f64, f64) {
+    let len = (x * x + y * y + z * z).sqrt();
+    (x / len, y / len, z / len)
+}
+
 /// Convert HSV to RGB
 fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u8, u8, u8) {
     let c = v * s;
@@ -225,8 +1041,54 @@ fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u8, u8, u8) {
     )
 }
 
+/// How points inside the set are coloured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InteriorMode {
+    /// A single colour, black unless the request gives `interior_colour`
+    #[default]
+    Solid,
+    /// Angle of the final z, shifted by its magnitude (the original
+    /// `colour_interior` look)
+    Angle,
+    /// Magnitude of the final z
+    Magnitude,
+    /// Period of the attracting cycle the orbit settles into
+    Period,
+    /// Magnitude of the final derivative dz/dc
+    Derivative,
+    /// Angle and magnitude of the mean of the whole orbit
+    OrbitAverage,
+}
+
+impl InteriorMode {
+    /// Deserialize a mode, or the older `colour_interior` flag: `true` was
+    /// the angle colouring and `false` solid black
+    pub fn deserialize_compat<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ModeOrFlag;
+
+        impl<'de> serde::de::Visitor<'de> for ModeOrFlag {
+            type Value = InteriorMode;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an interior mode or a boolean")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, flag: bool) -> Result<InteriorMode, E> {
+                Ok(if flag { InteriorMode::Angle } else { InteriorMode::Solid })
+            }
+
+            fn visit_str<E: serde::de::Error>(self, mode: &str) -> Result<InteriorMode, E> {
+                InteriorMode::deserialize(serde::de::value::StrDeserializer::new(mode))
+            }
+        }
+
+        deserializer.deserialize_any(ModeOrFlag)
+    }
+}
+
 /// Colour the interior of the Mandelbrot set based on final orbit position
-pub fn colour_interior(final_x: f64, final_y: f64, palette: &[(u8, u8, u8)]) -> (u8, u8, u8) {
+pub fn colour_interior(final_x: f64, final_y: f64, palette: &[Rgb]) -> Rgb {
     // Use angle of final position for colouring
     let angle = final_y.atan2(final_x);
     let normalised = (angle + std::f64::consts::PI) / (2.0 * std::f64::consts::PI);
@@ -239,21 +1101,656 @@ pub fn colour_interior(final_x: f64, final_y: f64, palette: &[(u8, u8, u8)]) ->
     palette[idx]
 }
 
+/// Curve applied to the smooth iteration count before it is scaled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Transfer {
+    #[default]
+    Linear,
+    Sqrt,
+    CubeRoot,
+    /// ln(1 + n), for very deep zooms
+    Log,
+    /// n raised to `ColourMapping::exponent`
+    Power,
+}
+
+/// Maps smooth iteration counts to palette positions
+///
+/// The palette index is `transfer(n) * density + offset * palette length`,
+/// wrapping round the palette. Offset is in whole palette cycles, so
+/// animating it from 0 to 1 rotates the colours once.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ColourMapping {
+    #[serde(default)]
+    pub transfer: Transfer,
+    /// Palette entries per unit of transferred iteration count
+    #[serde(default = "default_density")]
+    pub density: f64,
+    #[serde(default)]
+    pub offset: f64,
+    /// Exponent for `Transfer::Power`
+    #[serde(default = "default_exponent")]
+    pub exponent: f64,
+}
+
+fn default_density() -> f64 {
+    0.1
+}
+
+fn default_exponent() -> f64 {
+    0.5
+}
+
+impl Default for ColourMapping {
+    fn default() -> Self {
+        Self {
+            transfer: Transfer::default(),
+            density: default_density(),
+            offset: 0.0,
+            exponent: default_exponent(),
+        }
+    }
+}
+
+impl ColourMapping {
+    /// Fractional palette index for a smooth iteration count, in `0..len`
+    #[inline]
+    pub fn position(&self, smooth_iter: f64, palette_len: usize) -> f64 {
+        let n = smooth_iter.max(0.0);
+        let transferred = match self.transfer {
+            Transfer::Linear => n,
+            Transfer::Sqrt => n.sqrt(),
+            Transfer::CubeRoot => n.cbrt(),
+            Transfer::Log => n.ln_1p(),
+            Transfer::Power => n.powf(self.exponent),
+        };
+        let len = palette_len as f64;
+        (transferred * self.density + self.offset * len).rem_euclid(len)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.density.is_finite() && self.density > 0.0) {
+            return Err(format!("Colour density must be positive, got {}", self.density));
+        }
+        if !self.offset.is_finite() {
+            return Err("Colour offset must be finite".to_string());
+        }
+        if !(self.exponent.is_finite() && self.exponent > 0.0) {
+            return Err(format!("Transfer exponent must be positive, got {}", self.exponent));
+        }
+        Ok(())
+    }
+}
+
+/// How escaped points are mapped onto the palette
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Colouring {
+    /// Fixed palette density per iteration, coloured by the workers
+    #[default]
+    Smooth,
+    /// Iteration counts equalised over the whole frame by the coordinator
+    Histogram,
+}
+
+/// Recolour escaped pixels through the frame's iteration distribution
+///
+/// Each escaped pixel's position in the cumulative distribution of smooth
+/// iteration counts picks its place along the palette, so every part of the
+/// palette covers roughly the same number of pixels however deep the frame.
+/// Pixels with a negative iteration value (the interior) are left alone.
+/// `width` is the frame width, for placing the dither pattern.
+pub fn histogram_colour(
+    pixels: &mut [u8],
+    format: PixelFormat,
+    dither: Dither,
+    width: u32,
+    iterations: &[f32],
+    palette: &[Rgb],
+) {
+    let mut sorted: Vec<f32> = iterations.iter().copied().filter(|&i| i >= 0.0).collect();
+    if sorted.is_empty() {
+        return;
+    }
+    sorted.sort_unstable_by(f32::total_cmp);
+
+    let count = sorted.len() as f64;
+    let last = (palette.len() - 1) as f64;
+    for (i, (pixel, &iteration)) in pixels.chunks_exact_mut(format.bytes_per_pixel()).zip(iterations).enumerate() {
+        if iteration < 0.0 {
+            continue;
+        }
+        let rank = sorted.partition_point(|&i| i < iteration);
+        let position = rank as f64 / count * last;
+
+        let idx1 = position.floor() as usize;
+        let idx2 = (idx1 + 1).min(palette.len() - 1);
+        let frac = position.fract() as f32;
+        let (from, to) = (palette[idx1], palette[idx2]);
+        let threshold = dither.threshold(i as u32 % width, i as u32 / width);
+        format.write_dithered([0, 1, 2].map(|c| from[c] + (to[c] - from[c]) * frac), threshold, pixel);
+    }
+}
+
+/// Colour vision deficiency to simulate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColourVision {
+    /// No long-wavelength (red) cones
+    Protanopia,
+    /// No medium-wavelength (green) cones
+    Deuteranopia,
+    /// No short-wavelength (blue) cones
+    Tritanopia,
+}
+
+impl ColourVision {
+    /// Full-severity matrices from Machado, Oliveira and Fernandes (2009),
+    /// applied to linear RGB
+    fn matrix(self) -> [[f64; 3]; 3] {
+        match self {
+            ColourVision::Protanopia => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            ColourVision::Deuteranopia => [
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ],
+            ColourVision::Tritanopia => [
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ],
+        }
+    }
+
+    /// How an sRGB colour appears with this deficiency
+    pub fn simulate(self, colour: Rgb) -> Rgb {
+        let linear = colour.map(|c| srgb_to_linear(c as f64));
+        self.matrix().map(|row| {
+            let c = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+            linear_to_srgb(c.clamp(0.0, 1.0)) as f32
+        })
+    }
+}
+
+/// Draw a palette as a horizontal strip of 8-bit RGB pixels, one palette
+/// cycle across the width
+pub fn palette_strip(palette: &Palette, width: u32, height: u32, vision: Option<ColourVision>) -> Vec<u8> {
+    let mut row = vec![0u8; width as usize * 3];
+    for (colour, out) in palette.generate(width as usize).into_iter().zip(row.chunks_exact_mut(3)) {
+        let colour = vision.map_or(colour, |vision| vision.simulate(colour));
+        PixelFormat::Rgb8.write(colour, out);
+    }
+    row.repeat(height as usize)
+}
+
+/// Most colouring layers a frame may stack
+pub const MAX_LAYERS: usize = 8;
+
+/// How a colouring layer combines with the layers beneath it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+}
+
+impl BlendMode {
+    /// Blend `layer` over `backdrop`, then mix the result in by `opacity`
+    ///
+    /// Channels are blended as stored (sRGB-encoded), using the W3C
+    /// compositing formulas.
+    pub fn apply(self, backdrop: Rgb, layer: Rgb, opacity: f32) -> Rgb {
+        [0, 1, 2].map(|c| {
+            let (a, b) = (backdrop[c], layer[c]);
+            let blended = match self {
+                BlendMode::Normal => b,
+                BlendMode::Multiply => a * b,
+                BlendMode::Screen => a + b - a * b,
+                BlendMode::Overlay if a <= 0.5 => 2.0 * a * b,
+                BlendMode::Overlay => 1.0 - 2.0 * (1.0 - a) * (1.0 - b),
+                BlendMode::SoftLight if b <= 0.5 => a - (1.0 - 2.0 * b) * a * (1.0 - a),
+                BlendMode::SoftLight => {
+                    let d = if a <= 0.25 { ((16.0 * a - 12.0) * a + 4.0) * a } else { a.sqrt() };
+                    a + (2.0 * b - 1.0) * (d - a)
+                }
+            };
+            a + (blended - a) * opacity
+        })
+    }
+}
+
+/// Shape an orbit trap measures the orbit's distance from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TrapShape {
+    /// Distance from the trap centre
+    #[default]
+    Point,
+    /// Distance from the nearer of the horizontal and vertical lines through the centre
+    Cross,
+    /// Distance from a circle of `radius` around the centre
+    Circle,
+}
+
+/// Closest approach of the orbit to a shape, used as a colouring value
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OrbitTrap {
+    #[serde(default)]
+    pub shape: TrapShape,
+    #[serde(default)]
+    pub x: f64,
+    #[serde(default)]
+    pub y: f64,
+    #[serde(default = "default_trap_radius")]
+    pub radius: f64,
+    /// Palette cycles per unit of distance
+    #[serde(default = "default_trap_scale")]
+    pub scale: f64,
+}
+
+fn default_trap_radius() -> f64 {
+    1.0
+}
+
+fn default_trap_scale() -> f64 {
+    1.0
+}
+
+impl OrbitTrap {
+    #[inline]
+    pub fn distance(&self, x: f64, y: f64) -> f64 {
+        let (dx, dy) = (x - self.x, y - self.y);
+        match self.shape {
+            TrapShape::Point => dx.hypot(dy),
+            TrapShape::Cross => dx.abs().min(dy.abs()),
+            TrapShape::Circle => (dx.hypot(dy) - self.radius).abs(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.x.is_finite() && self.y.is_finite() && self.radius.is_finite()) {
+            return Err("Orbit trap position and radius must be finite".to_string());
+        }
+        if !(self.scale.is_finite() && self.scale > 0.0) {
+            return Err(format!("Orbit trap scale must be positive, got {}", self.scale));
+        }
+        Ok(())
+    }
+}
+
+/// What a colouring layer draws
+///
+/// Each source covers part of the image; elsewhere the layer is skipped and
+/// the layers beneath show through.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LayerSource {
+    /// Smooth iteration count through the palette (escaped points)
+    Smooth,
+    /// Grey shading from the distance estimate, black at the boundary of
+    /// the set and white a pixel or more away (escaped points)
+    DistanceEstimate,
+    /// Closest approach to a trap through the palette (all points)
+    OrbitTrap(OrbitTrap),
+    /// Interior colouring through the palette (points in the set)
+    Interior {
+        #[serde(default = "default_interior_layer")]
+        mode: InteriorMode,
+    },
+}
+
+fn default_interior_layer() -> InteriorMode {
+    InteriorMode::Angle
+}
+
+/// One layer of a frame's colouring stack
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub source: LayerSource,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    /// Palette for this layer; the frame palette if unset
+    #[serde(default)]
+    pub palette: Option<Palette>,
+    /// Set by the coordinator in place of `palette` when sending to workers
+    #[serde(default)]
+    pub palette_hash: Option<u64>,
+}
+
+fn default_opacity() -> f32 {
+    1.0
+}
+
+impl Layer {
+    pub fn new(source: LayerSource) -> Self {
+        Self {
+            source,
+            blend: BlendMode::default(),
+            opacity: default_opacity(),
+            palette: None,
+            palette_hash: None,
+        }
+    }
+
+    /// The stack used by frames that don't list layers: smooth colouring
+    /// outside the set and the request's interior mode inside it
+    pub fn defaults(interior: InteriorMode) -> Vec<Layer> {
+        vec![
+            Layer::new(LayerSource::Smooth),
+            Layer::new(LayerSource::Interior { mode: interior }),
+        ]
+    }
+
+    /// Check a stack of layers, which may hold at most one orbit trap
+    pub fn validate_stack(layers: &[Layer]) -> Result<(), String> {
+        if layers.len() > MAX_LAYERS {
+            return Err(format!("At most {} colouring layers are allowed, got {}", MAX_LAYERS, layers.len()));
+        }
+        for layer in layers {
+            if !(0.0..=1.0).contains(&layer.opacity) {
+                return Err(format!("Layer opacity must be between 0 and 1, got {}", layer.opacity));
+            }
+            if let LayerSource::OrbitTrap(trap) = &layer.source {
+                trap.validate()?;
+            }
+        }
+        if layers.iter().filter(|l| matches!(l.source, LayerSource::OrbitTrap(_))).count() > 1 {
+            return Err("Only one orbit trap layer is supported".to_string());
+        }
+        Ok(())
+    }
+
+    /// The trap the orbit must be measured against, if any layer uses one
+    pub fn orbit_trap(layers: &[Layer]) -> Option<OrbitTrap> {
+        layers.iter().find_map(|layer| match layer.source {
+            LayerSource::OrbitTrap(trap) => Some(trap),
+            _ => None,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Quantise to 8-bit, for readable expectations
+    fn bytes(colour: Rgb) -> [u8; 3] {
+        let mut out = [0u8; 3];
+        PixelFormat::Rgb8.write(colour, &mut out);
+        out
+    }
+
+    fn all_bytes(palette: &[Rgb]) -> Vec<[u8; 3]> {
+        palette.iter().map(|&c| bytes(c)).collect()
+    }
+
     #[test]
     fn test_palette_generation() {
-        for palette_type in Palette::all() {
-            let palette = palette_type.generate(256);
+        for preset in Preset::all() {
+            let palette = Palette::Preset(*preset).generate(256);
             assert_eq!(palette.len(), 256);
 
-            for (r, g, b) in &palette {
-                assert!(*r <= 255);
-                assert!(*g <= 255);
-                assert!(*b <= 255);
+            // Every palette should actually vary across its range
+            assert!(palette.iter().any(|c| *c != palette[0]));
+        }
+    }
+
+    #[test]
+    fn test_palette_hash_key() {
+        let gradient = |colour| {
+            Palette::Gradient(Gradient {
+                stops: vec![ColourStop { position: 0.0, colour }],
+                interpolation: Interpolation::Linear,
+                space: ColourSpace::Srgb,
+            })
+        };
+        assert_eq!(gradient([1, 2, 3]).hash_key(), gradient([1, 2, 3]).hash_key());
+        assert_ne!(gradient([1, 2, 3]).hash_key(), gradient([1, 2, 4]).hash_key());
+        assert_ne!(Palette::Preset(Preset::Fire).hash_key(), Palette::Preset(Preset::Ocean).hash_key());
+    }
+
+    #[test]
+    fn test_gradient_stops() {
+        let stop = |position, colour| ColourStop { position, colour };
+        let mut gradient = Gradient {
+            // Deliberately out of order
+            stops: vec![stop(0.5, [200, 100, 0]), stop(0.0, [0, 0, 0])],
+            interpolation: Interpolation::Linear,
+            space: ColourSpace::Srgb,
+        };
+        assert!(gradient.validate().is_ok());
+
+        let palette = all_bytes(&gradient.generate(4));
+        assert_eq!(palette, vec![[0, 0, 0], [100, 50, 0], [200, 100, 0], [100, 50, 0]]);
+
+        gradient.interpolation = Interpolation::Step;
+        assert_eq!(bytes(gradient.generate(4)[1]), [0, 0, 0]);
+
+        gradient.stops.push(stop(1.5, [0, 0, 0]));
+        assert!(gradient.validate().is_err());
+
+        // Presets parse by name, gradients as objects
+        let preset: Palette = serde_json::from_str("\"ocean\"").unwrap();
+        assert_eq!(preset, Palette::Preset(Preset::Ocean));
+        let custom: Palette = serde_json::from_str(
+            r#"{"stops": [{"position": 0.0, "colour": [255, 0, 0]}], "interpolation": "smoothstep"}"#,
+        )
+        .unwrap();
+        assert_eq!(all_bytes(&custom.generate(3)), vec![[255, 0, 0]; 3]);
+    }
+
+    #[test]
+    fn test_import_palette_files() {
+        let map = "0 0 0 black\n255 128 0\n\n0 0 255 blue\n";
+        let imported = import_palettes("sunset.MAP", map).unwrap();
+        assert_eq!(imported[0].0, "sunset");
+        let colours: Vec<[u8; 3]> = imported[0].1.stops.iter().map(|s| s.colour).collect();
+        assert_eq!(colours, vec![[0, 0, 0], [255, 128, 0], [0, 0, 255]]);
+        assert!(parse_map("1 2\n").is_err());
+
+        let ggr = "GIMP Gradient\nName: Duo\n2\n\
+            0.0 0.25 0.5 0 0 0 1 1 0 0 1 0 0\n\
+            0.5 0.75 1.0 1 0 0 1 0 0 1 1 5 0\n";
+        let (name, gradient) = parse_ggr(ggr).unwrap();
+        assert_eq!(name.as_deref(), Some("Duo"));
+        let palette = all_bytes(&gradient.generate(4));
+        assert_eq!(palette[0], [0, 0, 0]);
+        assert_eq!(palette[1], [128, 0, 0]);
+        assert_eq!(palette[2], [255, 0, 0]);
+        // Second segment is a step blend at its midpoint
+        assert_eq!(palette[3], [0, 0, 255]);
+
+        let ugr = "first {\ngradient:\n  title=\"Blue to red\" smooth=no\n  index=0 color=16711680\n  \
+            index=200 color=255\nopacity:\n  index=0 opacity=255\n}\nsecond {\ngradient:\n  \
+            index=100 color=65280\n}\n";
+        let imported = import_palettes("lib.ugr", ugr).unwrap();
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[0].0, "Blue to red");
+        assert_eq!(imported[0].1.stops[0].colour, [0, 0, 255]);
+        assert_eq!(imported[0].1.stops[1], ColourStop { position: 0.5, colour: [255, 0, 0] });
+        assert_eq!(imported[1].0, "second");
+        assert_eq!(imported[1].1.stops[0].colour, [0, 255, 0]);
+
+        assert!(import_palettes("x.png", "").is_err());
+    }
+
+    #[test]
+    fn test_colour_mapping() {
+        let mut mapping = ColourMapping::default();
+        // Defaults keep the original fixed density
+        assert_eq!(mapping.position(25.0, 2048), 2.5);
+
+        mapping.offset = 0.25;
+        assert_eq!(mapping.position(25.0, 2048), 514.5);
+        mapping.offset = -1.0;
+        assert_eq!(mapping.position(25.0, 2048), 2.5);
+
+        mapping = ColourMapping { transfer: Transfer::Sqrt, density: 1.0, ..Default::default() };
+        assert_eq!(mapping.position(1e6, 2048), 1000.0);
+        mapping.transfer = Transfer::Log;
+        assert!(mapping.position(1e9, 2048) < 21.0);
+        mapping.transfer = Transfer::Power;
+        mapping.exponent = 2.0;
+        assert_eq!(mapping.position(3.0, 2048), 9.0);
+
+        mapping.density = 0.0;
+        assert!(mapping.validate().is_err());
+    }
+
+    #[test]
+    fn test_colour_space_blending() {
+        let (red, green) = ([255, 0, 0], [0, 255, 0]);
+        for space in [ColourSpace::Srgb, ColourSpace::LinearRgb, ColourSpace::Oklab, ColourSpace::Oklch] {
+            // Ends are reproduced exactly
+            assert_eq!(bytes(space.mix(red, green, 0.0)), red, "{:?}", space);
+            assert_eq!(bytes(space.mix(red, green, 1.0)), green, "{:?}", space);
+            assert_eq!(bytes(space.mix([90, 90, 90], [90, 90, 90], 0.5)), [90, 90, 90], "{:?}", space);
+        }
+
+        // sRGB byte blending gives a dark midpoint; the others stay brighter
+        assert_eq!(bytes(ColourSpace::Srgb.mix(red, green, 0.5)), [128, 128, 0]);
+        assert_eq!(bytes(ColourSpace::LinearRgb.mix(red, green, 0.5)), [188, 188, 0]);
+        let oklab = bytes(ColourSpace::Oklab.mix(red, green, 0.5));
+        assert!(oklab[0] > 128 && oklab[1] > 128);
+
+        // Black to blue in OKLCh keeps blue's hue throughout
+        let mid = ColourSpace::Oklch.mix([0, 0, 0], [0, 0, 255], 0.5);
+        assert!(mid[2] > mid[0] && mid[2] > mid[1]);
+    }
+
+    #[test]
+    fn test_lighting_faces_light() {
+        let lighting = Lighting {
+            angle: 0.0,
+            height: 1.0,
+            model: LightingModel::Lambert,
+            shininess: 32.0,
+        };
+        // Facing the light keeps full brightness, facing away leaves ambient
+        assert_eq!(lighting.shade([0.8, 0.4, 0.2], 1.0, 0.0), [0.8, 0.4, 0.2]);
+        assert_eq!(lighting.shade([0.8, 0.4, 0.2], -1.0, 0.0), [0.2, 0.1, 0.05]);
+    }
+
+    #[test]
+    fn test_histogram_spreads_palette() {
+        let palette: Vec<Rgb> = (0..=255).map(|i| [i as f32 / 255.0, 0.0, 0.0]).collect();
+        // Heavily skewed counts still span the whole palette, in order
+        let iterations = [1.0, 1.1, 1.2, 1.3, 500.0, -1.0];
+        let mut pixels = vec![7u8; iterations.len() * 6];
+        histogram_colour(&mut pixels, PixelFormat::Rgb16, Dither::None, 6, &iterations, &palette);
+
+        let reds: Vec<u16> = pixels.chunks(6).map(|p| u16::from_le_bytes([p[0], p[1]])).collect();
+        assert_eq!(reds[0], 0);
+        assert!(reds.windows(2).take(4).all(|w| w[0] < w[1]));
+        assert!(reds[4] >= 50000);
+        // Interior pixel untouched
+        assert_eq!(&pixels[30..36], &[7; 6]);
+    }
+
+    #[test]
+    fn test_colour_vision_simulation() {
+        // Neutral colours look the same to everyone
+        let distance = |a: Rgb, b: Rgb| (0..3).map(|c| (a[c] - b[c]).abs()).sum::<f32>();
+        for vision in [ColourVision::Protanopia, ColourVision::Deuteranopia, ColourVision::Tritanopia] {
+            assert_eq!(bytes(vision.simulate([1.0; 3])), [255; 3]);
+            assert!(distance(vision.simulate([0.5; 3]), [0.5; 3]) < 0.01);
+        }
+
+        // Without red or green cones, both red and green look yellowish
+        for vision in [ColourVision::Protanopia, ColourVision::Deuteranopia] {
+            for colour in [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]] {
+                let [r, g, _] = vision.simulate(colour);
+                assert!((r - g).abs() < 0.15, "{:?} {:?}", vision, colour);
+            }
+        }
+
+        // Sequential presets run there and back, so the cycle is seamless
+        let viridis = Preset::Viridis.colours(64);
+        assert_eq!(viridis[0], (68, 1, 84));
+        assert_eq!(viridis[32], (253, 231, 37));
+        assert_eq!(viridis[1], viridis[63]);
+
+        let strip = palette_strip(&Palette::Preset(Preset::Cividis), 16, 3, None);
+        assert_eq!(strip.len(), 16 * 3 * 3);
+        assert_eq!(strip[..48], strip[96..]);
+    }
+
+    #[test]
+    fn test_dither_thresholds() {
+        // Standard 8x8 Bayer matrix
+        let first_rows: Vec<u32> = (0..2).flat_map(|y| (0..8).map(move |x| bayer_rank(x, y))).collect();
+        assert_eq!(first_rows, [0, 32, 8, 40, 2, 34, 10, 42, 48, 16, 56, 24, 50, 18, 58, 26]);
+
+        // Both patterns use every threshold exactly once per tile
+        let mut ranks: Vec<u32> = (0..64).map(|i| bayer_rank(i % 8, i / 8)).collect();
+        ranks.sort_unstable();
+        assert!(ranks.iter().copied().eq(0..64));
+        let mut ranks = blue_noise_mask().to_vec();
+        ranks.sort_unstable();
+        assert!(ranks.iter().map(|&r| r as usize).eq(0..BLUE_NOISE_SIZE * BLUE_NOISE_SIZE));
+
+        // A level a quarter of the way between two steps dithers to a
+        // quarter of the pixels on the upper step, and tiles periodically
+        for dither in [Dither::Bayer, Dither::BlueNoise] {
+            let colour = [(100.25 / 255.0) as f32; 3];
+            let mut upper = 0;
+            for y in 0..64 {
+                for x in 0..64 {
+                    let mut out = [0u8; 3];
+                    PixelFormat::Rgb8.write_dithered(colour, dither.threshold(x, y), &mut out);
+                    assert!(out[0] == 100 || out[0] == 101);
+                    upper += (out[0] == 101) as u32;
+                }
             }
+            assert_eq!(upper, 64 * 64 / 4, "{:?}", dither);
+            assert_eq!(dither.threshold(3, 5), dither.threshold(3 + 64, 5 + 128));
         }
+        assert_eq!(Dither::None.threshold(3, 5), 0.0);
+    }
+
+    #[test]
+    fn test_interior_mode_accepts_flag() {
+        let parse = |value| InteriorMode::deserialize_compat(value).unwrap();
+        assert_eq!(parse(serde_json::json!(true)), InteriorMode::Angle);
+        assert_eq!(parse(serde_json::json!(false)), InteriorMode::Solid);
+        assert_eq!(parse(serde_json::json!("orbit_average")), InteriorMode::OrbitAverage);
+        assert!(InteriorMode::deserialize_compat(serde_json::json!("sideways")).is_err());
+    }
+
+    #[test]
+    fn test_blend_modes() {
+        let backdrop = [0.2, 0.5, 0.8];
+        let layer = [0.5, 0.5, 0.5];
+        assert_eq!(BlendMode::Normal.apply(backdrop, layer, 1.0), layer);
+        assert_eq!(bytes(BlendMode::Multiply.apply(backdrop, layer, 1.0)), bytes([0.1, 0.25, 0.4]));
+        assert_eq!(bytes(BlendMode::Screen.apply(backdrop, layer, 1.0)), bytes([0.6, 0.75, 0.9]));
+        // Mid-grey is neutral for overlay and soft light
+        assert_eq!(bytes(BlendMode::Overlay.apply(backdrop, layer, 1.0)), bytes(backdrop));
+        assert_eq!(bytes(BlendMode::SoftLight.apply(backdrop, layer, 1.0)), bytes(backdrop));
+        // Opacity mixes between the backdrop and the blended result
+        assert_eq!(BlendMode::Normal.apply(backdrop, layer, 0.0), backdrop);
+        assert_eq!(bytes(BlendMode::Multiply.apply([1.0; 3], [0.0; 3], 0.25)), bytes([0.75; 3]));
+
+        let layers: Vec<Layer> = serde_json::from_str(
+            r#"[{"source": {"type": "smooth"}},
+                {"source": {"type": "orbit_trap", "shape": "cross"}, "blend": "soft_light", "palette": "ocean"},
+                {"source": {"type": "orbit_trap"}, "opacity": 0.5}]"#,
+        )
+        .unwrap();
+        assert_eq!(layers[1].palette, Some(Palette::Preset(Preset::Ocean)));
+        assert!(Layer::validate_stack(&layers[..2]).is_ok());
+        assert!(Layer::validate_stack(&layers).is_err());
     }
 }
@@ -1,14 +1,25 @@
-/// Coordinator module - manages workers, assigns work, assembles frames
+//! Coordinator module - manages workers, assigns work, assembles frames
 
 use axum::extract::ws::{Message, WebSocket};
 use base64::Engine;
 use futures_util::{SinkExt, StreamExt};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, oneshot};
 
+use crate::colour::{
+    histogram_colour, import_palettes, palette_strip, ColourVision, Colouring, Dither, Gradient, Layer, LayerSource,
+    Palette, PixelFormat, Preset, PALETTE_SIZE,
+};
+use crate::extract::{extract_gradient, Extraction};
+use crate::image::{decode_rgb, encode_png};
+use crate::formula::{FormulaError, Program};
+use crate::mandelbrot::{fill_pass, pass_samples, INTERIOR, PROGRESSIVE_STEPS};
 use crate::messages::*;
+use crate::palette_cache::PaletteCache;
+use crate::reproject::{ReprojectionPlan, RetainedFrame};
+use crate::scheduler::Scheduler;
 
 /// Profile dimensions - fixed area for consistent benchmarking
 const PROFILE_WIDTH: u32 = 512;
@@ -20,40 +31,286 @@ const PROFILE_INTERVAL_SECS: u64 = 60;
 /// Worker timeout - remove if no heartbeat in this time
 const WORKER_TIMEOUT_SECS: u64 = 30;
 
+/// Edge length of the square tiles a frame is cut into
+const TILE_SIZE: u32 = 128;
+
+/// Work units a worker holds at once, from any frames; it is sent the next as
+/// each returns, so it never sits idle waiting on the round trip
+const WORKER_QUEUE_DEPTH: usize = 3;
+
+/// A tile is requeued if its worker sends nothing at all in this time
+const STRIP_TIMEOUT_SECS: u64 = 10;
+
+/// How often to look for tiles held by lost or unresponsive workers
+const WATCHDOG_INTERVAL_MS: u64 = 500;
+
+/// A frame fails if it isn't finished this long after its first tile is sent
+const FRAME_TIMEOUT_SECS: u64 = 30;
+
+/// A frame fails once any of its tiles has failed to render this many times
+const STRIP_ATTEMPTS: u32 = 2;
+
 /// Information about a connected worker
 struct WorkerInfo {
-    sender: mpsc::Sender<CoordinatorToWorker>,
+    sender: mpsc::UnboundedSender<CoordinatorToWorker>,  // Unbounded so tiles can be sent in dispatch order
     capability: f64,  // Higher = faster (inverse of profile time)
     last_seen: Instant,
-    busy: bool,
+    assigned: HashSet<WorkUnit>,  // Units sent but not yet returned, at most WORKER_QUEUE_DEPTH
+    last_delivery: Instant,  // When it last returned any strip data
+    palettes: PaletteCache<()>,  // Mirror of the worker's palette cache
+}
+
+/// Rectangular region of a frame, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Tile {
+    x_start: u32,
+    x_end: u32,
+    y_start: u32,
+    y_end: u32,
+}
+
+impl Tile {
+    /// Cut a frame into square tiles of `size` pixels, row-major
+    ///
+    /// Tiles along the right and bottom edges are clipped to the frame.
+    fn split(width: u32, height: u32, size: u32) -> Vec<Tile> {
+        let mut tiles = Vec::new();
+        for y_start in (0..height).step_by(size as usize) {
+            for x_start in (0..width).step_by(size as usize) {
+                tiles.push(Tile {
+                    x_start,
+                    x_end: (x_start + size).min(width),
+                    y_start,
+                    y_end: (y_start + size).min(height),
+                });
+            }
+        }
+        tiles
+    }
+}
+
+/// A tile of a pending frame: the frame id and the tile's (x_start, y_start)
+type WorkUnit = (u64, (u32, u32));
+
+/// Tile not yet returned at full resolution
+struct OwedStrip {
+    worker_id: Option<String>,  // None while waiting in the work queue
+    sent_at: Instant,
+    pass: Option<u32>,  // Highest refinement pass received, from any worker
+    avoid: Option<String>,  // Unresponsive or failing worker the tile was taken back from
+    failures: u32,  // Renders that failed outright, on any worker
+    cost: f64,  // Pixel-iterations, for scheduling between sessions
+    request: RenderStripRequest,  // Kept so the tile can be sent again
 }
 
 /// Pending frame being assembled
+///
+/// Tiles are copied into `pixels` as they arrive, so the buffer always holds
+/// the best data received so far and can be sent as a preview.
 struct PendingFrame {
     width: u32,
     height: u32,
-    strips: HashMap<u32, Vec<u8>>,  // y_start -> pixel data
+    format: PixelFormat,
+    colouring: Colouring,  // Histogram previews are recoloured like the finished frame
+    dither: Dither,
+    pixels: Vec<u8>,
+    iterations: Option<Vec<f32>>,  // Kept for reprojected and histogram-coloured frames
+    reused: Option<Vec<bool>>,  // Pixels filled from the previous frame, not by workers
+    owed: HashMap<(u32, u32), OwedStrip>,  // By (x_start, y_start); complete when empty
+    session: u64,  // Client session the frame is scheduled under
+    weight: f64,  // Scheduling weight from the request's priority
+    palettes: PaletteDefinitions,  // Defined on any worker a tile is sent to
     expected_strips: usize,
+    pass_counts: Vec<usize>,  // Tiles that have delivered each refinement pass
     start_time: Instant,
-    response_tx: oneshot::Sender<FrameResponse>,
+    response_tx: oneshot::Sender<Result<FinishedFrame, FrameError>>,
+    dispatched_tx: Option<oneshot::Sender<()>>,  // Fired when the first tile is sent
+    pass_tx: Option<mpsc::Sender<CoordinatorToClient>>,  // Set for progressive frames
+}
+
+/// Fully assembled frame, handed back to the requesting client
+struct FinishedFrame {
+    pixels: Vec<u8>,
+    iterations: Option<Vec<f32>>,
+    render_ms: u64,
+}
+
+/// Palettes workers need defined for a frame, by hash
+type PaletteDefinitions = Vec<(u64, Palette)>;
+
+/// Why a frame request failed
+pub enum FrameError {
+    /// Reported to the client as a plain error message
+    Failed(String),
+    /// The custom formula didn't parse
+    Formula(FormulaError),
+    /// The client cancelled the frame
+    Cancelled(u64),
+}
+
+impl From<String> for FrameError {
+    fn from(message: String) -> Self {
+        FrameError::Failed(message)
+    }
+}
+
+impl From<FrameError> for CoordinatorToClient {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Failed(message) => CoordinatorToClient::Error { message },
+            FrameError::Formula(e) => CoordinatorToClient::FormulaError {
+                message: e.message,
+                start: e.start,
+                end: e.end,
+            },
+            FrameError::Cancelled(frame_id) => CoordinatorToClient::FrameCancelled { frame_id },
+        }
+    }
+}
+
+/// Per-connection client state
+pub struct ClientSession {
+    id: u64,
+    tx: mpsc::Sender<CoordinatorToClient>,
+    last_frame: Option<RetainedFrame>,  // For zoom reprojection
+    waiting: Arc<RwLock<HashSet<u64>>>,  // Frames accepted but not yet started; cancelling removes them
 }
 
 /// Coordinator state
 pub struct Coordinator {
     workers: RwLock<HashMap<String, WorkerInfo>>,
     pending_frames: RwLock<HashMap<u64, PendingFrame>>,
+    queue: RwLock<Scheduler<WorkUnit>>,  // Tiles waiting for a worker, per client session
     next_frame_id: RwLock<u64>,
+    next_session_id: RwLock<u64>,
     frames_rendered: RwLock<u64>,
+    palettes: RwLock<HashMap<String, Gradient>>,  // Imported palettes by name
 }
 
 impl Coordinator {
     pub fn new() -> Arc<Self> {
-        Arc::new(Self {
-            workers: RwLock::new(HashMap::new()),
-            pending_frames: RwLock::new(HashMap::new()),
-            next_frame_id: RwLock::new(0),
-            frames_rendered: RwLock::new(0),
-        })
+        Arc::new(Self::default())
+    }
+
+    /// Import the palettes in a palette file and make them available by name
+    ///
+    /// Returns the names registered. Existing palettes with the same name are
+    /// replaced; the built-in preset names are reserved.
+    pub fn register_palettes(&self, file_name: &str, text: &str) -> Result<Vec<String>, String> {
+        self.add_palettes(import_palettes(file_name, text)?)
+    }
+
+    /// Build a gradient from the colours of a PNG or PPM image and register it as `name`
+    pub fn extract_palette(&self, name: &str, image: &[u8], extraction: &Extraction) -> Result<Gradient, String> {
+        if name.is_empty() {
+            return Err("Palette name is empty".to_string());
+        }
+        let gradient = extract_gradient(&decode_rgb(image)?, extraction)?;
+        self.add_palettes(vec![(name.to_string(), gradient.clone())])?;
+        Ok(gradient)
+    }
+
+    fn add_palettes(&self, imported: Vec<(String, Gradient)>) -> Result<Vec<String>, String> {
+        for (name, gradient) in &imported {
+            if serde_json::from_value::<Preset>(serde_json::Value::String(name.clone())).is_ok() {
+                return Err(format!("'{}' is a built-in palette name", name));
+            }
+            gradient.validate()?;
+        }
+
+        let mut palettes = self.palettes.write().unwrap();
+        Ok(imported
+            .into_iter()
+            .map(|(name, gradient)| {
+                tracing::info!("Registered palette '{}' ({} stops)", name, gradient.stops.len());
+                palettes.insert(name.clone(), gradient);
+                name
+            })
+            .collect())
+    }
+
+    /// Register every palette file in a directory
+    pub fn load_palette_dir(&self, dir: &std::path::Path) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::info!("No palettes loaded from {}: {}", dir.display(), e);
+                return;
+            }
+        };
+
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+            // Older palette files aren't always UTF-8; the formats only need ASCII
+            let text = match std::fs::read(&path) {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                Err(e) => {
+                    tracing::warn!("Failed to read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.register_palettes(file_name, &text) {
+                tracing::warn!("Skipping palette file {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Names of the imported palettes, sorted
+    pub fn palette_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.palettes.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Replace a palette name with the imported gradient it refers to
+    fn resolve_palette(&self, palette: &Palette) -> Result<Palette, String> {
+        match palette {
+            Palette::Named(name) => match self.palettes.read().unwrap().get(name) {
+                Some(gradient) => Ok(Palette::Gradient(gradient.clone())),
+                None => Err(format!("Unknown palette '{}'", name)),
+            },
+            _ => {
+                palette.validate()?;
+                Ok(palette.clone())
+            }
+        }
+    }
+
+    /// Render a palette, built-in, imported or inline, as a PNG gradient strip
+    pub fn palette_preview(
+        &self,
+        palette: &Palette,
+        width: u32,
+        height: u32,
+        vision: Option<ColourVision>,
+    ) -> Result<Vec<u8>, String> {
+        let palette = self.resolve_palette(palette)?;
+        encode_png(width, height, &palette_strip(&palette, width, height, vision))
+    }
+
+    /// Check a frame's colouring layers and swap each layer palette for its hash
+    ///
+    /// Returns the layers as workers receive them, and the distinct layer
+    /// palettes they need defined.
+    fn resolve_layers(&self, request: &FrameRequest) -> Result<(Vec<Layer>, PaletteDefinitions), String> {
+        Layer::validate_stack(&request.layers)?;
+        if !request.layers.is_empty() && request.colouring == Colouring::Histogram {
+            return Err("Histogram colouring can't be combined with colouring layers".to_string());
+        }
+
+        let mut layers = request.layers.clone();
+        let mut palettes = PaletteDefinitions::new();
+        for layer in &mut layers {
+            if let Some(palette) = layer.palette.take() {
+                let palette = self.resolve_palette(&palette)?;
+                let hash = palette.hash_key();
+                if !palettes.iter().any(|(known, _)| *known == hash) {
+                    palettes.push((hash, palette));
+                }
+                layer.palette_hash = Some(hash);
+            }
+        }
+        Ok((layers, palettes))
     }
 
     /// Start the profiling loop
@@ -72,7 +329,7 @@ impl Coordinator {
     /// Run profiling on all workers
     async fn run_profiling(&self) {
         // Collect senders while holding lock, then release before awaiting
-        let senders: Vec<mpsc::Sender<CoordinatorToWorker>> = {
+        let senders: Vec<mpsc::UnboundedSender<CoordinatorToWorker>> = {
             let workers = self.workers.read().unwrap();
             tracing::info!("Running profiling on {} workers", workers.len());
             workers.values().map(|w| w.sender.clone()).collect()
@@ -84,7 +341,7 @@ impl Coordinator {
         };
 
         for sender in senders {
-            let _ = sender.send(msg.clone()).await;
+            let _ = sender.send(msg.clone());
         }
     }
 
@@ -109,7 +366,7 @@ impl Coordinator {
     /// Handle a new worker connection
     pub async fn handle_worker_connection(self: &Arc<Self>, socket: WebSocket) {
         let (ws_sender, mut ws_receiver) = socket.split();
-        let (tx, rx) = mpsc::channel::<CoordinatorToWorker>(32);
+        let (tx, rx) = mpsc::unbounded_channel::<CoordinatorToWorker>();
 
         // Spawn task to forward messages to WebSocket
         let ws_sender = Arc::new(tokio::sync::Mutex::new(ws_sender));
@@ -119,7 +376,7 @@ impl Coordinator {
             while let Some(msg) = rx.recv().await {
                 let text = serde_json::to_string(&msg).unwrap();
                 let mut sender = ws_sender_clone.lock().await;
-                if sender.send(Message::Text(text.into())).await.is_err() {
+                if sender.send(Message::Text(text)).await.is_err() {
                     break;
                 }
             }
@@ -160,18 +417,21 @@ impl Coordinator {
                             sender: tx.clone(),
                             capability: 1.0,  // Default until profiled
                             last_seen: Instant::now(),
-                            busy: false,
+                            assigned: HashSet::new(),
+                            last_delivery: Instant::now(),
+                            palettes: PaletteCache::new(),
                         });
                     }
 
                     // Send acknowledgement
-                    let _ = tx.send(CoordinatorToWorker::Registered { worker_id: id.clone() }).await;
+                    let _ = tx.send(CoordinatorToWorker::Registered { worker_id: id.clone() });
 
                     // Request initial profiling
                     let _ = tx.send(CoordinatorToWorker::RunProfile {
                         width: PROFILE_WIDTH,
                         height: PROFILE_HEIGHT,
-                    }).await;
+                    });
+                    coordinator.dispatch().await;
                 }
 
                 WorkerToCoordinator::Heartbeat { worker_id: id } => {
@@ -192,24 +452,68 @@ impl Coordinator {
                 WorkerToCoordinator::StripResult(result) => {
                     coordinator.handle_strip_result(result).await;
                 }
+
+                WorkerToCoordinator::StripAbandoned { worker_id, frame_id, x_start, y_start } => {
+                    coordinator.handle_strip_abandoned(&worker_id, (frame_id, (x_start, y_start))).await;
+                }
+
+                WorkerToCoordinator::StripFailed { worker_id, frame_id, x_start, y_start, message } => {
+                    coordinator.handle_strip_failed(&worker_id, (frame_id, (x_start, y_start)), &message).await;
+                }
             }
         }
 
         // Worker disconnected - remove from pool
         if let Some(id) = worker_id {
             tracing::info!("Worker disconnected: {}", id);
-            coordinator.workers.write().unwrap().remove(&id);
+            coordinator.remove_worker(&id).await;
+        }
+    }
+
+    /// Remove a worker from the pool and requeue the tiles it held
+    ///
+    /// Should that leave no workers, frames none of whose tiles have gone out
+    /// fail now rather than wait for one forever. Frames already started
+    /// still have their timeout, and keep their tiles in case a worker joins.
+    async fn remove_worker(&self, worker_id: &str) {
+        self.workers.write().unwrap().remove(worker_id);
+        self.requeue_strips().await;
+
+        let stranded: Vec<u64> = {
+            let pending = self.pending_frames.read().unwrap();
+            if !self.workers.read().unwrap().is_empty() {
+                return;
+            }
+            pending
+                .iter()
+                .filter(|(_, frame)| frame.dispatched_tx.is_some())
+                .map(|(frame_id, _)| *frame_id)
+                .collect()
+        };
+        if !stranded.is_empty() {
+            tracing::warn!("No workers left for frames {:?}", stranded);
+            self.abandon_frames(&stranded, Some("No workers available"));
         }
     }
 
-    /// Handle a completed strip from a worker
+    /// Handle a completed strip from a worker, and send it its next unit
     async fn handle_strip_result(&self, result: StripResult) {
-        // Mark worker as not busy
         if let Some(worker) = self.workers.write().unwrap().get_mut(&result.worker_id) {
-            worker.busy = false;
             worker.last_seen = Instant::now();
+            worker.last_delivery = Instant::now();
+            if result.last_pass {
+                worker.assigned.remove(&(result.frame_id, (result.x_start, result.y_start)));
+            }
         }
+        self.assemble_strip(&result);
+        self.dispatch().await;
+    }
 
+    /// Copy a strip into its pending frame, finishing the frame once every tile is in
+    ///
+    /// A tile is taken from whichever worker delivers it first, so a requeued
+    /// tile still counts if its original worker turns out to be merely slow.
+    fn assemble_strip(&self, result: &StripResult) {
         // Decode the strip data
         let pixel_data = match base64::engine::general_purpose::STANDARD.decode(&result.data) {
             Ok(d) => d,
@@ -219,164 +523,553 @@ impl Coordinator {
             }
         };
 
+        let iteration_data = match result.iterations.as_deref().map(decode_iterations) {
+            Some(Ok(d)) => Some(d),
+            Some(Err(e)) => {
+                tracing::error!("Failed to decode strip iterations: {}", e);
+                return;
+            }
+            None => None,
+        };
+
         // Add to pending frame
         let mut pending = self.pending_frames.write().unwrap();
         if let Some(frame) = pending.get_mut(&result.frame_id) {
-            frame.strips.insert(result.y_start, pixel_data);
+            let key = (result.x_start, result.y_start);
+            let Some(owed) = frame.owed.get_mut(&key) else {
+                return;
+            };
+            // A tile sent twice repeats passes already received for it
+            if owed.pass.is_some_and(|pass| result.pass <= pass) && !result.last_pass {
+                return;
+            }
+
+            // The tile's bounds are the ones it was sent with, whatever the worker reports
+            let request = &owed.request;
+            let tile = Tile {
+                x_start: request.x_start,
+                x_end: request.x_end,
+                y_start: request.y_start,
+                y_end: request.y_end,
+            };
+            let (width, height) = (tile.x_end - tile.x_start, tile.y_end - tile.y_start);
+            let pixels = if !request.progressive {
+                (width * height) as usize
+            } else if (result.pass as usize) < PROGRESSIVE_STEPS.len() {
+                pass_samples(width, height, result.pass as usize).count()
+            } else {
+                0
+            };
+            if pixels == 0
+                || pixel_data.len() != pixels * frame.format.bytes_per_pixel()
+                || iteration_data.as_ref().is_some_and(|data| data.len() != pixels)
+            {
+                tracing::warn!(
+                    "Discarding malformed tile ({}, {}) of frame {} from worker {}",
+                    key.0,
+                    key.1,
+                    result.frame_id,
+                    result.worker_id
+                );
+                return;
+            }
+            owed.pass = Some(result.pass);
+
+            let bytes_per_pixel = frame.format.bytes_per_pixel();
+            if owed.request.progressive {
+                // Each pass carries only its new samples
+                let origin = (tile.x_start, tile.y_start);
+                let size = (tile.x_end - tile.x_start, tile.y_end - tile.y_start);
+                let pass = result.pass as usize;
+                fill_pass(&mut frame.pixels, frame.width, bytes_per_pixel, origin, size, pass, &pixel_data);
+                if let (Some(iterations), Some(data)) = (frame.iterations.as_mut(), &iteration_data) {
+                    fill_pass(iterations, frame.width, 1, origin, size, pass, data);
+                }
+            } else {
+                let reused = frame.reused.as_deref();
+                blit_tile(&mut frame.pixels, frame.width, bytes_per_pixel, &tile, &pixel_data, reused);
+                if let (Some(iterations), Some(data)) = (frame.iterations.as_mut(), &iteration_data) {
+                    blit_tile(iterations, frame.width, 1, &tile, data, reused);
+                }
+            }
+
+            if result.last_pass {
+                frame.owed.remove(&key);
+            } else if let Some(count) = frame.pass_counts.get_mut(result.pass as usize) {
+                // Once every tile has reached this pass, show the client a preview
+                *count += 1;
+                if *count == frame.expected_strips {
+                    if let Some(pass_tx) = &frame.pass_tx {
+                        let mut pixels = frame.pixels.clone();
+                        if let (Colouring::Histogram, Some(iterations)) = (frame.colouring, &frame.iterations) {
+                            let palette = frame.palettes[0].1.generate(PALETTE_SIZE);
+                            let (format, dither) = (frame.format, frame.dither);
+                            histogram_colour(&mut pixels, format, dither, frame.width, iterations, &palette);
+                        }
+                        let preview = CoordinatorToClient::FramePass(FramePassResponse {
+                            frame_id: result.frame_id,
+                            pass: result.pass,
+                            width: frame.width,
+                            height: frame.height,
+                            format: frame.format,
+                            data: base64::engine::general_purpose::STANDARD.encode(&pixels),
+                        });
+                        // A dropped preview is harmless; the full frame follows
+                        let _ = pass_tx.try_send(preview);
+                    }
+                }
+            }
 
             // Check if frame is complete
-            if frame.strips.len() == frame.expected_strips {
-                // Assemble the frame
-                let assembled = self.assemble_frame(frame);
+            if frame.owed.is_empty() {
                 let render_ms = frame.start_time.elapsed().as_millis() as u64;
 
-                let response = FrameResponse {
-                    frame_id: result.frame_id,
-                    width: frame.width,
-                    height: frame.height,
-                    render_ms,
-                    data: base64::engine::general_purpose::STANDARD.encode(&assembled),
-                };
-
                 // Send response (take ownership of response_tx)
                 if let Some(frame) = pending.remove(&result.frame_id) {
-                    let _ = frame.response_tx.send(response);
+                    let _ = frame.response_tx.send(Ok(FinishedFrame {
+                        pixels: frame.pixels,
+                        iterations: frame.iterations,
+                        render_ms,
+                    }));
                     *self.frames_rendered.write().unwrap() += 1;
                 }
             }
         }
     }
 
-    /// Assemble strips into a complete frame
-    fn assemble_frame(&self, frame: &PendingFrame) -> Vec<u8> {
-        let mut assembled = vec![0u8; (frame.width * frame.height * 3) as usize];
-
-        // Sort strips by y_start and copy into assembled buffer
-        let mut sorted_strips: Vec<_> = frame.strips.iter().collect();
-        sorted_strips.sort_by_key(|(y_start, _)| *y_start);
-
-        for (y_start, data) in sorted_strips {
-            let offset = (*y_start * frame.width * 3) as usize;
-            let end = offset + data.len();
-            if end <= assembled.len() {
-                assembled[offset..end].copy_from_slice(data);
-            }
-        }
-
-        assembled
-    }
-
     /// Handle a client frame request
-    pub async fn request_frame(&self, request: FrameRequest) -> Result<FrameResponse, String> {
-        let frame_id = {
-            let mut id = self.next_frame_id.write().unwrap();
-            let current = *id;
-            *id += 1;
-            current
-        };
-
-        // Get available workers and their capabilities
-        let workers: Vec<(String, f64, mpsc::Sender<CoordinatorToWorker>)> = {
-            let workers = self.workers.read().unwrap();
-            workers.iter()
-                .filter(|(_, info)| !info.busy)
-                .map(|(id, info)| (id.clone(), info.capability, info.sender.clone()))
-                .collect()
-        };
-
-        if workers.is_empty() {
-            return Err("No workers available".to_string());
+    ///
+    /// For progressive requests, previews of each completed refinement pass
+    /// are sent to the client while the frame is being rendered. Reprojected
+    /// requests reuse what they can of the session's previous frame.
+    pub async fn request_frame(
+        &self,
+        request: FrameRequest,
+        frame_id: u64,
+        session: &mut ClientSession,
+    ) -> Result<FrameResponse, FrameError> {
+        // Cancelled while it waited behind the client's earlier frames
+        if !session.waiting.read().unwrap().contains(&frame_id) {
+            return Err(FrameError::Cancelled(frame_id));
         }
+        request.formula.validate()?;
+        request.bailout.validate()?;
+        let palette = self.resolve_palette(&request.palette)?;
+        request.colour_mapping.validate()?;
+        let (layers, layer_palettes) = self.resolve_layers(&request)?;
+        let program = match &request.custom_formula {
+            Some(source) => Some(Program::compile(source).map_err(FrameError::Formula)?),
+            None => None,
+        };
 
-        // Calculate total capability for proportional distribution
-        let total_capability: f64 = workers.iter().map(|(_, c, _)| c).sum();
+        let start_time = Instant::now();
+        // Progressive and exponential-map frames are always rendered from
+        // scratch, as are distance-shaded frames since the shading is relative
+        // to the pixel size
+        let palette_hash = palette.hash_key();
+        let mut definitions = vec![(palette_hash, palette.clone())];
+        definitions.extend(layer_palettes);
+        let palette_hashes: Vec<u64> = definitions.iter().map(|(hash, _)| *hash).collect();
 
-        // Assign strips to workers proportionally
-        let mut strip_assignments = Vec::new();
-        let mut current_y = 0u32;
+        let reproject = request.reproject
+            && !request.progressive
+            && request.projection == Projection::Rectilinear
+            && !request.layers.iter().any(|layer| layer.source == LayerSource::DistanceEstimate);
+        let mut plan = if reproject {
+            Some(ReprojectionPlan::new(&request, &palette_hashes, session.last_frame.as_ref()))
+        } else {
+            None
+        };
+        session.last_frame = None;
 
-        for (i, (worker_id, capability, sender)) in workers.iter().enumerate() {
-            let proportion = capability / total_capability;
-            let strip_height = if i == workers.len() - 1 {
-                // Last worker gets the remainder
-                request.height - current_y
-            } else {
-                ((request.height as f64) * proportion).round() as u32
+        // Work out which tiles still need rendering, and how
+        let mut tiles = Vec::new();
+        for tile in Tile::split(request.width, request.height, TILE_SIZE) {
+            let mut pixels = (tile.x_end - tile.x_start) * (tile.y_end - tile.y_start);
+            let reprojection = match &plan {
+                Some(plan) => {
+                    let skip: Vec<bool> = (tile.y_start..tile.y_end)
+                        .flat_map(|y| {
+                            let row = (y * request.width) as usize;
+                            plan.reused[row + tile.x_start as usize..row + tile.x_end as usize].iter().copied()
+                        })
+                        .collect();
+                    if skip.iter().all(|&s| s) {
+                        continue;
+                    }
+                    pixels -= skip.iter().filter(|&&s| s).count() as u32;
+                    Some(Reprojection::new(
+                        plan.xs[tile.x_start as usize..tile.x_end as usize].to_vec(),
+                        plan.ys[tile.y_start as usize..tile.y_end as usize].to_vec(),
+                        &skip,
+                    ))
+                }
+                None => None,
             };
+            // Iterations are bounded by max_iterations, which is as good an estimate as any
+            let cost = pixels as f64 * request.max_iterations.max(1) as f64;
+            tiles.push((tile, reprojection, cost));
+        }
 
-            if strip_height > 0 && current_y < request.height {
-                let y_end = (current_y + strip_height).min(request.height);
-                strip_assignments.push((
-                    worker_id.clone(),
-                    sender.clone(),
-                    current_y,
-                    y_end,
-                ));
-                current_y = y_end;
+        // Nothing left to compute - the previous frame covers this view
+        if tiles.is_empty() {
+            if let Some(mut plan) = plan.take() {
+                if !session.waiting.write().unwrap().remove(&frame_id) {
+                    return Err(FrameError::Cancelled(frame_id));
+                }
+                let mut finished = FinishedFrame {
+                    pixels: std::mem::take(&mut plan.pixels),
+                    iterations: Some(std::mem::take(&mut plan.iterations)),
+                    render_ms: start_time.elapsed().as_millis() as u64,
+                };
+                apply_colouring(&request, &palette, &mut finished);
+                *self.frames_rendered.write().unwrap() += 1;
+                return Ok(self.finish_frame(frame_id, &request, palette_hashes, plan, finished, session));
             }
         }
 
-        if strip_assignments.is_empty() {
-            return Err("Failed to assign strips".to_string());
+        let strips: Vec<(RenderStripRequest, f64)> = tiles
+            .into_iter()
+            .map(|(tile, reprojection, cost)| {
+                let strip = RenderStripRequest {
+                    frame_id,
+                    width: request.width,
+                    x_start: tile.x_start,
+                    x_end: tile.x_end,
+                    y_start: tile.y_start,
+                    y_end: tile.y_end,
+                    total_height: request.height,
+                    center_x: request.center_x,
+                    center_y: request.center_y,
+                    zoom: request.zoom,
+                    max_iterations: request.max_iterations,
+                    palette_hash,
+                    interior: request.interior,
+                    interior_colour: request.interior_colour,
+                    progressive: request.progressive,
+                    lighting: request.lighting,
+                    projection: request.projection,
+                    formula: request.formula.clone(),
+                    program: program.clone(),
+                    bailout: request.bailout,
+                    return_iterations: reprojection.is_none() && request.colouring == Colouring::Histogram,
+                    colour_mapping: request.colour_mapping,
+                    pixel_format: request.pixel_format,
+                    layers: layers.clone(),
+                    dither: request.dither,
+                    reprojection,
+                };
+                (strip, cost)
+            })
+            .collect();
+
+        if strips.is_empty() {
+            return Err(FrameError::Failed("Failed to assign strips".to_string()));
         }
 
-        // Create pending frame
+        // Create pending frame, starting from whatever the previous frame supplies
         let (response_tx, response_rx) = oneshot::channel();
+        let (dispatched_tx, dispatched_rx) = oneshot::channel();
         {
+            let (pixels, iterations, reused) = match plan.as_mut() {
+                Some(plan) => (
+                    std::mem::take(&mut plan.pixels),
+                    Some(std::mem::take(&mut plan.iterations)),
+                    Some(std::mem::take(&mut plan.reused)),
+                ),
+                None => {
+                    let count = (request.width * request.height) as usize;
+                    let iterations = (request.colouring == Colouring::Histogram).then(|| vec![INTERIOR; count]);
+                    (vec![0u8; count * request.pixel_format.bytes_per_pixel()], iterations, None)
+                }
+            };
             let mut pending = self.pending_frames.write().unwrap();
+            // A cancel either finds the frame still waiting or, once this lock
+            // is released, pending
+            if !session.waiting.write().unwrap().remove(&frame_id) {
+                return Err(FrameError::Cancelled(frame_id));
+            }
+            let mut queue = self.queue.write().unwrap();
+            // Frames queue behind each other, but with no workers at all this
+            // one never would start. Checked under the lock so a departing
+            // last worker either sees this frame or is seen here.
+            if self.workers.read().unwrap().is_empty() {
+                return Err(FrameError::Failed("No workers available".to_string()));
+            }
+            let passes = if request.progressive { PROGRESSIVE_STEPS.len() } else { 1 };
+            let expected_strips = strips.len();
+            let owed = strips
+                .into_iter()
+                .map(|(strip, cost)| {
+                    let key = (strip.x_start, strip.y_start);
+                    queue.push(session.id, (frame_id, key), cost, request.priority.weight());
+                    let owed = OwedStrip {
+                        worker_id: None,
+                        sent_at: Instant::now(),
+                        pass: None,
+                        avoid: None,
+                        failures: 0,
+                        cost,
+                        request: strip,
+                    };
+                    (key, owed)
+                })
+                .collect();
             pending.insert(frame_id, PendingFrame {
                 width: request.width,
                 height: request.height,
-                strips: HashMap::new(),
-                expected_strips: strip_assignments.len(),
-                start_time: Instant::now(),
+                format: request.pixel_format,
+                colouring: request.colouring,
+                dither: request.dither,
+                pixels,
+                iterations,
+                reused,
+                owed,
+                session: session.id,
+                weight: request.priority.weight(),
+                palettes: definitions,
+                expected_strips,
+                pass_counts: vec![0; passes - 1],
+                start_time,
                 response_tx,
+                dispatched_tx: Some(dispatched_tx),
+                pass_tx: request.progressive.then(|| session.tx.clone()),
             });
         }
+        self.dispatch().await;
+
+        // Wait for the response, timing out only from when the first tile is
+        // sent so a frame waiting its turn behind other clients' frames isn't
+        // cut short. Should the frame be dropped first, the response channel
+        // reports it.
+        let _ = dispatched_rx.await;
+        match tokio::time::timeout(Duration::from_secs(FRAME_TIMEOUT_SECS), response_rx).await {
+            Ok(Ok(Ok(mut finished))) => {
+                apply_colouring(&request, &palette, &mut finished);
+                Ok(match plan {
+                    Some(plan) => self.finish_frame(frame_id, &request, palette_hashes, plan, finished, session),
+                    None => frame_response(frame_id, &request, &finished),
+                })
+            }
+            Ok(Ok(Err(e))) => Err(e),
+            // The pending frame was dropped by `cancel_frames`
+            Ok(Err(_)) => Err(FrameError::Cancelled(frame_id)),
+            Err(_) => {
+                self.abandon_frames(&[frame_id], None);
+                Err(FrameError::Failed("Frame render timeout".to_string()))
+            }
+        }
+    }
+
+    /// Cancel one of a session's frames, or all of its frames in flight
+    pub fn cancel_frames(&self, session: u64, frame_id: Option<u64>) {
+        let frame_ids: Vec<u64> = self
+            .pending_frames
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(id, frame)| frame.session == session && frame_id.is_none_or(|wanted| wanted == **id))
+            .map(|(id, _)| *id)
+            .collect();
+        if !frame_ids.is_empty() {
+            tracing::info!("Cancelling frames {:?}", frame_ids);
+            self.abandon_frames(&frame_ids, None);
+        }
+    }
+
+    /// Drop pending frames and tell the workers holding their tiles to abandon them
+    ///
+    /// Queued tiles are discarded when they come up. A worker keeps each
+    /// slot until it reports the tile abandoned or finished, as it may still be
+    /// rendering it. Anyone waiting on a frame is sent `failure` as an error,
+    /// or without one sees its sender dropped and takes the frame as cancelled.
+    fn abandon_frames(&self, frame_ids: &[u64], failure: Option<&str>) {
+        let mut pending = self.pending_frames.write().unwrap();
+        let workers = self.workers.read().unwrap();
+        for frame_id in frame_ids {
+            let Some(frame) = pending.remove(frame_id) else {
+                continue;
+            };
+            if let Some(message) = failure {
+                let _ = frame.response_tx.send(Err(FrameError::Failed(message.to_string())));
+            }
+            for worker in workers.values() {
+                if worker.assigned.iter().any(|(assigned_frame, _)| assigned_frame == frame_id) {
+                    let _ = worker.sender.send(CoordinatorToWorker::CancelFrame { frame_id: *frame_id });
+                }
+            }
+        }
+    }
+
+    /// Free the slot of a tile a worker gave up on because its frame was
+    /// cancelled
+    async fn handle_strip_abandoned(&self, worker_id: &str, unit: WorkUnit) {
+        if let Some(worker) = self.workers.write().unwrap().get_mut(worker_id) {
+            worker.last_seen = Instant::now();
+            worker.assigned.remove(&unit);
+        }
+        self.dispatch().await;
+    }
 
-        // Mark workers as busy and send requests
+    /// Free the slot of a tile a worker failed to render, and try it again
+    /// elsewhere; the frame fails once the tile has failed `STRIP_ATTEMPTS`
+    /// times, as it likely fails the same way on every worker
+    async fn handle_strip_failed(&self, worker_id: &str, unit: WorkUnit, message: &str) {
+        tracing::warn!("Worker {} failed tile {:?} of frame {}: {}", worker_id, unit.1, unit.0, message);
+        let mut failed = false;
         {
+            let mut pending = self.pending_frames.write().unwrap();
+            let mut queue = self.queue.write().unwrap();
             let mut workers = self.workers.write().unwrap();
-            for (worker_id, _, _, _) in &strip_assignments {
-                if let Some(worker) = workers.get_mut(worker_id) {
-                    worker.busy = true;
+            if let Some(worker) = workers.get_mut(worker_id) {
+                worker.last_seen = Instant::now();
+                worker.assigned.remove(&unit);
+            }
+            if let Some(frame) = pending.get_mut(&unit.0) {
+                if let Some(owed) = frame.owed.get_mut(&unit.1) {
+                    if owed.worker_id.as_deref() == Some(worker_id) {
+                        owed.failures += 1;
+                        owed.worker_id = None;
+                        owed.avoid = Some(worker_id.to_string());
+                        failed = owed.failures >= STRIP_ATTEMPTS;
+                        if !failed {
+                            queue.push_front(frame.session, unit, owed.cost, frame.weight);
+                        }
+                    }
                 }
             }
         }
+        if failed {
+            let (x, y) = unit.1;
+            let failure = format!("Tile ({}, {}) failed to render: {}", x, y, message);
+            self.abandon_frames(&[unit.0], Some(&failure));
+        }
+        self.dispatch().await;
+    }
 
-        // Send render requests to workers
-        for (worker_id, sender, y_start, y_end) in strip_assignments {
-            let msg = CoordinatorToWorker::RenderStrip(RenderStripRequest {
-                frame_id,
-                width: request.width,
-                y_start,
-                y_end,
-                total_height: request.height,
-                center_x: request.center_x,
-                center_y: request.center_y,
-                zoom: request.zoom,
-                max_iterations: request.max_iterations,
-                palette: request.palette,
-                colour_interior: request.colour_interior,
-            });
+    /// Start requeueing tiles held by workers that have gone or stopped responding
+    pub fn start_strip_watchdog(self: &Arc<Self>) {
+        let coordinator = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(WATCHDOG_INTERVAL_MS));
+            loop {
+                interval.tick().await;
+                coordinator.requeue_strips().await;
+            }
+        });
+    }
 
-            if let Err(e) = sender.send(msg).await {
-                tracing::error!("Failed to send to worker {}: {}", worker_id, e);
+    /// Put tiles held by disconnected or timed-out workers back at the front
+    /// of the work queue, so their frames still complete
+    ///
+    /// A worker has timed out when a tile has waited on it for the timeout and
+    /// it has delivered nothing else meanwhile; tiles queued behind others on a
+    /// busy worker are left alone. A timed-out worker keeps its slot until it
+    /// returns the tile, so the tile goes to another worker if there is one free.
+    async fn requeue_strips(&self) {
+        let timeout = Duration::from_secs(STRIP_TIMEOUT_SECS);
+        {
+            // Lock order is always pending frames, queue, then workers
+            let mut pending = self.pending_frames.write().unwrap();
+            let mut queue = self.queue.write().unwrap();
+            let workers = self.workers.read().unwrap();
+            for (frame_id, frame) in pending.iter_mut() {
+                for (key, owed) in frame.owed.iter_mut() {
+                    let Some(worker_id) = &owed.worker_id else { continue };
+                    let lost = match workers.get(worker_id) {
+                        Some(worker) => {
+                            if owed.sent_at.elapsed() < timeout || worker.last_delivery.elapsed() < timeout {
+                                continue;
+                            }
+                            false
+                        }
+                        None => true,
+                    };
+                    tracing::warn!(
+                        "Requeueing tile ({}, {}) of frame {} from {} worker {}",
+                        key.0,
+                        key.1,
+                        frame_id,
+                        if lost { "lost" } else { "unresponsive" },
+                        worker_id
+                    );
+                    let worker_id = owed.worker_id.take();
+                    if !lost {
+                        owed.avoid = worker_id;
+                    }
+                    queue.push_front(frame.session, (*frame_id, *key), owed.cost, frame.weight);
+                }
             }
         }
+        self.dispatch().await;
+    }
 
-        // Wait for response with timeout
-        match tokio::time::timeout(Duration::from_secs(30), response_rx).await {
-            Ok(Ok(response)) => Ok(response),
-            Ok(Err(_)) => Err("Frame assembly cancelled".to_string()),
-            Err(_) => {
-                // Timeout - clean up pending frame
-                self.pending_frames.write().unwrap().remove(&frame_id);
-                Err("Frame render timeout".to_string())
+    /// Send queued tiles to workers with free slots, to whichever would get
+    /// through its queue soonest
+    ///
+    /// Tiles are taken fairly between client sessions and in order within
+    /// each, so a worker finishing one frame already holds the first tiles of
+    /// the next.
+    async fn dispatch(&self) {
+        {
+            let mut pending = self.pending_frames.write().unwrap();
+            let mut queue = self.queue.write().unwrap();
+            let mut workers = self.workers.write().unwrap();
+            while workers.values().any(|worker| worker.assigned.len() < WORKER_QUEUE_DEPTH) {
+                // Skip units of frames that have finished or timed out, and tiles
+                // that were requeued but then delivered by their first worker
+                let waiting = |unit: &WorkUnit| {
+                    let owed = pending.get(&unit.0).and_then(|frame| frame.owed.get(&unit.1));
+                    owed.is_some_and(|owed| owed.worker_id.is_none())
+                };
+                let Some(unit) = queue.next(waiting) else {
+                    break;
+                };
+                let frame = pending.get_mut(&unit.0).unwrap();
+                let owed = frame.owed.get_mut(&unit.1).unwrap();
+
+                // A tile taken back from an unresponsive or failing worker only
+                // returns to it if no other worker has room
+                let avoid = owed.avoid.as_deref();
+                let (worker_id, worker) = workers
+                    .iter_mut()
+                    .filter(|(_, worker)| worker.assigned.len() < WORKER_QUEUE_DEPTH)
+                    .min_by(|a, b| {
+                        let load_a = (a.1.assigned.len() + 1) as f64 / a.1.capability;
+                        let load_b = (b.1.assigned.len() + 1) as f64 / b.1.capability;
+                        let avoid_a = avoid == Some(a.0.as_str());
+                        let avoid_b = avoid == Some(b.0.as_str());
+                        avoid_a.cmp(&avoid_b).then(load_a.total_cmp(&load_b))
+                    })
+                    .unwrap();
+                owed.worker_id = Some(worker_id.clone());
+                owed.sent_at = Instant::now();
+                if let Some(dispatched_tx) = frame.dispatched_tx.take() {
+                    let _ = dispatched_tx.send(());
+                }
+                worker.assigned.insert(unit);
+                send_strip(worker_id, worker, &frame.palettes, owed.request.clone());
             }
         }
     }
 
+    /// Build the response for a reprojected frame and keep it for the next one
+    fn finish_frame(
+        &self,
+        frame_id: u64,
+        request: &FrameRequest,
+        palette_hashes: Vec<u64>,
+        plan: ReprojectionPlan,
+        finished: FinishedFrame,
+        session: &mut ClientSession,
+    ) -> FrameResponse {
+        let response = frame_response(frame_id, request, &finished);
+        if let Some(iterations) = finished.iterations {
+            session.last_frame = Some(plan.retain(request, palette_hashes, finished.pixels, iterations));
+        }
+        response
+    }
+
     /// Get current status
     pub fn get_status(&self) -> StatusResponse {
         let workers = self.workers.read().unwrap();
@@ -396,15 +1089,61 @@ impl Coordinator {
 
     /// Handle a client WebSocket connection
     pub async fn handle_client_connection(self: &Arc<Self>, socket: WebSocket) {
-        let (mut sender, mut receiver) = socket.split();
+        let (ws_sender, mut receiver) = socket.split();
+        let (tx, rx) = mpsc::channel::<CoordinatorToClient>(32);
+        let id = {
+            let mut id = self.next_session_id.write().unwrap();
+            *id += 1;
+            *id
+        };
+        // Frame requests are rendered one at a time, in order, by a task that
+        // owns the session, so this loop can read cancellations meanwhile
+        let waiting = Arc::new(RwLock::new(HashSet::new()));
+        let (requests_tx, mut requests_rx) = mpsc::unbounded_channel::<(u64, FrameRequest)>();
+        let mut session = ClientSession {
+            id,
+            tx: tx.clone(),
+            last_frame: None,
+            waiting: Arc::clone(&waiting),
+        };
+        let coordinator = Arc::clone(self);
+        tokio::spawn(async move {
+            while let Some((frame_id, request)) = requests_rx.recv().await {
+                let response = match coordinator.request_frame(request, frame_id, &mut session).await {
+                    Ok(frame) => CoordinatorToClient::Frame(frame),
+                    Err(e) => e.into(),
+                };
+                // However the request ended, its id has nothing left to cancel
+                session.waiting.write().unwrap().remove(&frame_id);
+                if session.tx.send(response).await.is_err() {
+                    break;
+                }
+            }
+        });
 
         tracing::info!("Client connected");
 
+        // Spawn task to forward messages to WebSocket, so progressive previews
+        // can be sent while a frame request is still in flight
+        let ws_sender = Arc::new(tokio::sync::Mutex::new(ws_sender));
+        let ws_sender_clone = Arc::clone(&ws_sender);
+        tokio::spawn(async move {
+            let mut rx = rx;
+            while let Some(msg) = rx.recv().await {
+                let text = serde_json::to_string(&msg).unwrap();
+                let mut sender = ws_sender_clone.lock().await;
+                if sender.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
         while let Some(msg) = receiver.next().await {
             let msg = match msg {
                 Ok(Message::Text(text)) => text,
                 Ok(Message::Close(_)) => break,
                 Ok(Message::Ping(data)) => {
+                    let mut sender = ws_sender.lock().await;
                     let _ = sender.send(Message::Pong(data)).await;
                     continue;
                 }
@@ -417,29 +1156,49 @@ impl Coordinator {
                     let error = CoordinatorToClient::Error {
                         message: format!("Invalid message: {}", e),
                     };
-                    let _ = sender.send(Message::Text(serde_json::to_string(&error).unwrap().into())).await;
+                    let _ = tx.send(error).await;
                     continue;
                 }
             };
 
             let response = match parsed {
                 ClientToCoordinator::RequestFrame(req) => {
-                    match self.request_frame(req).await {
-                        Ok(frame) => CoordinatorToClient::Frame(frame),
-                        Err(e) => CoordinatorToClient::Error { message: e },
+                    // The id is given out at once so the client can cancel a
+                    // frame still queued behind its others
+                    let frame_id = {
+                        let mut next = self.next_frame_id.write().unwrap();
+                        *next += 1;
+                        *next
+                    };
+                    waiting.write().unwrap().insert(frame_id);
+                    let _ = tx.send(CoordinatorToClient::FrameAccepted { frame_id }).await;
+                    let _ = requests_tx.send((frame_id, *req));
+                    continue;
+                }
+                ClientToCoordinator::CancelFrame { frame_id } => {
+                    // Queued requests are dropped when their turn comes
+                    match frame_id {
+                        Some(frame_id) => {
+                            waiting.write().unwrap().remove(&frame_id);
+                        }
+                        None => waiting.write().unwrap().clear(),
                     }
+                    self.cancel_frames(id, frame_id);
+                    continue;
                 }
                 ClientToCoordinator::GetStatus => {
                     CoordinatorToClient::Status(self.get_status())
                 }
             };
 
-            let text = serde_json::to_string(&response).unwrap();
-            if sender.send(Message::Text(text.into())).await.is_err() {
+            if tx.send(response).await.is_err() {
                 break;
             }
         }
 
+        // Nobody is waiting for the rest of this client's frames
+        waiting.write().unwrap().clear();
+        self.cancel_frames(id, None);
         tracing::info!("Client disconnected");
     }
 }
@@ -449,8 +1208,379 @@ impl Default for Coordinator {
         Self {
             workers: RwLock::new(HashMap::new()),
             pending_frames: RwLock::new(HashMap::new()),
+            queue: RwLock::new(Scheduler::new()),
+            next_session_id: RwLock::new(0),
             next_frame_id: RwLock::new(0),
             frames_rendered: RwLock::new(0),
+            palettes: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+/// Second colouring phase, once every tile of the frame has arrived
+///
+/// Histogram colouring needs the iteration counts of the whole frame, so
+/// workers only supply them and the pixels are recoloured here.
+fn apply_colouring(request: &FrameRequest, palette: &Palette, finished: &mut FinishedFrame) {
+    if request.colouring != Colouring::Histogram {
+        return;
+    }
+    if let Some(iterations) = &finished.iterations {
+        let palette = palette.generate(PALETTE_SIZE);
+        histogram_colour(
+            &mut finished.pixels,
+            request.pixel_format,
+            request.dither,
+            request.width,
+            iterations,
+            &palette,
+        );
+    }
+}
+
+/// Send a tile to a worker along with any of its palettes the worker lacks
+///
+/// Called with the workers locked, so tiles reach each worker in the order
+/// its palette cache mirror was updated in.
+fn send_strip(worker_id: &str, worker: &mut WorkerInfo, definitions: &PaletteDefinitions, strip: RenderStripRequest) {
+    let mut palettes = Vec::new();
+    for hash in strip.palette_hashes() {
+        if worker.palettes.get(hash).is_none() {
+            worker.palettes.insert(hash, ());
+            if let Some(definition) = definitions.iter().find(|(known, _)| *known == hash) {
+                palettes.push(definition.clone());
+            }
+        }
+    }
+    let message = CoordinatorToWorker::RenderStrip { strip: Box::new(strip), palettes };
+    if let Err(e) = worker.sender.send(message) {
+        tracing::error!("Failed to send to worker {}: {}", worker_id, e);
+    }
+}
+
+/// Encode a finished frame for the client
+fn frame_response(frame_id: u64, request: &FrameRequest, finished: &FinishedFrame) -> FrameResponse {
+    FrameResponse {
+        frame_id,
+        width: request.width,
+        height: request.height,
+        render_ms: finished.render_ms,
+        format: request.pixel_format,
+        data: base64::engine::general_purpose::STANDARD.encode(&finished.pixels),
+    }
+}
+
+/// Copy a tile's rows into a frame buffer `frame_width` pixels wide
+///
+/// Each pixel is `channels` values. Pixels flagged in `reused` (one flag per
+/// frame pixel) keep the value already in the frame.
+fn blit_tile<T: Copy>(
+    frame: &mut [T],
+    frame_width: u32,
+    channels: usize,
+    tile: &Tile,
+    data: &[T],
+    reused: Option<&[bool]>,
+) {
+    let tile_width = (tile.x_end - tile.x_start) as usize;
+    let row_len = tile_width * channels;
+    for (row, src) in data.chunks_exact(row_len).enumerate() {
+        let y = tile.y_start as usize + row;
+        let first = y * frame_width as usize + tile.x_start as usize;
+        let Some(dst) = frame.get_mut(first * channels..(first + tile_width) * channels) else {
+            continue;
+        };
+        match reused {
+            None => dst.copy_from_slice(src),
+            Some(reused) => {
+                for x in 0..tile_width {
+                    if !reused.get(first + x).copied().unwrap_or(false) {
+                        dst[x * channels..(x + 1) * channels]
+                            .copy_from_slice(&src[x * channels..(x + 1) * channels]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_frame() {
+        let (width, height) = (300, 130);
+        let tiles = Tile::split(width, height, TILE_SIZE);
+        assert_eq!(tiles.len(), 3 * 2);
+
+        // Fill each tile with its index and check every pixel is written once
+        let mut frame = vec![0u8; (width * height * 3) as usize];
+        for (i, tile) in tiles.iter().enumerate() {
+            let len = ((tile.x_end - tile.x_start) * (tile.y_end - tile.y_start) * 3) as usize;
+            blit_tile(&mut frame, width, 3, tile, &vec![i as u8 + 1; len], None);
+        }
+        assert!(frame.iter().all(|&b| b != 0));
+        assert_eq!(frame[((129 * width + 299) * 3) as usize], 6);
+    }
+
+    /// Register a stand-in worker and return the channel its messages arrive on
+    fn fake_worker(coordinator: &Coordinator, id: &str) -> mpsc::UnboundedReceiver<CoordinatorToWorker> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        coordinator.workers.write().unwrap().insert(id.to_string(), WorkerInfo {
+            sender,
+            capability: 1.0,
+            last_seen: Instant::now(),
+            assigned: HashSet::new(),
+            last_delivery: Instant::now(),
+            palettes: PaletteCache::new(),
+        });
+        receiver
+    }
+
+    /// Next tile sent to a worker
+    async fn next_strip(receiver: &mut mpsc::UnboundedReceiver<CoordinatorToWorker>) -> RenderStripRequest {
+        match receiver.recv().await.unwrap() {
+            CoordinatorToWorker::RenderStrip { strip, .. } => *strip,
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    fn strip_result(worker_id: &str, strip: &RenderStripRequest) -> StripResult {
+        let len = ((strip.x_end - strip.x_start) * (strip.y_end - strip.y_start) * 3) as usize;
+        StripResult {
+            worker_id: worker_id.to_string(),
+            frame_id: strip.frame_id,
+            x_start: strip.x_start,
+            x_end: strip.x_end,
+            y_start: strip.y_start,
+            y_end: strip.y_end,
+            pass: 0,
+            last_pass: true,
+            compute_ms: 1,
+            data: base64::engine::general_purpose::STANDARD.encode(vec![7u8; len]),
+            iterations: None,
+        }
+    }
+
+    /// Start rendering a frame `tiles` tiles wide and one tile high
+    fn start_frame(
+        coordinator: &Arc<Coordinator>,
+        tiles: u32,
+    ) -> tokio::task::JoinHandle<Result<FrameResponse, FrameError>> {
+        let request: FrameRequest = serde_json::from_value(serde_json::json!({
+            "width": tiles * TILE_SIZE, "height": TILE_SIZE,
+            "center_x": -0.5, "center_y": 0.0, "zoom": 1.0, "max_iterations": 50
+        }))
+        .unwrap();
+        let frame_id = {
+            let mut next = coordinator.next_frame_id.write().unwrap();
+            *next += 1;
+            *next
+        };
+        let coordinator = Arc::clone(coordinator);
+        tokio::spawn(async move {
+            let (tx, _rx) = mpsc::channel(8);
+            let waiting = Arc::new(RwLock::new(HashSet::from([frame_id])));
+            let mut session = ClientSession { id: 1, tx, last_frame: None, waiting };
+            coordinator.request_frame(request, frame_id, &mut session).await
+        })
+    }
+
+    #[tokio::test]
+    async fn test_workers_pull_tiles_as_they_finish() {
+        let coordinator = Coordinator::new();
+        let mut fast = fake_worker(&coordinator, "fast");
+        let mut slow = fake_worker(&coordinator, "slow");
+        let frame = start_frame(&coordinator, 2 * WORKER_QUEUE_DEPTH as u32 + 1);
+
+        // Each worker's queue fills up; the last tile waits for whoever finishes first
+        let mut held = Vec::new();
+        for _ in 0..WORKER_QUEUE_DEPTH {
+            held.push(("fast", next_strip(&mut fast).await));
+            held.push(("slow", next_strip(&mut slow).await));
+        }
+        assert!(fast.try_recv().is_err() && slow.try_recv().is_err());
+        coordinator.handle_strip_result(strip_result("fast", &held[0].1)).await;
+        held[0] = ("fast", next_strip(&mut fast).await);
+        assert!(slow.try_recv().is_err());
+
+        for (worker_id, strip) in &held {
+            coordinator.handle_strip_result(strip_result(worker_id, strip)).await;
+        }
+        assert!(frame.await.unwrap().is_ok());
+        assert!(coordinator.queue.write().unwrap().next(|_| true).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_frames_share_workers() {
+        let coordinator = Coordinator::new();
+        let mut worker = fake_worker(&coordinator, "only");
+
+        // A second frame is queued rather than rejected, and its first tile is
+        // already with the worker before the first frame is done
+        let first = start_frame(&coordinator, 2);
+        let first_tiles = [next_strip(&mut worker).await, next_strip(&mut worker).await];
+        let second = start_frame(&coordinator, 2);
+        let pipelined = next_strip(&mut worker).await;
+        assert_ne!(pipelined.frame_id, first_tiles[0].frame_id);
+
+        for strip in &first_tiles {
+            coordinator.handle_strip_result(strip_result("only", strip)).await;
+        }
+        let last = next_strip(&mut worker).await;
+        for strip in [&pipelined, &last] {
+            coordinator.handle_strip_result(strip_result("only", strip)).await;
+        }
+        assert!(first.await.unwrap().is_ok());
+        assert!(second.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_frame_frees_workers() {
+        let coordinator = Coordinator::new();
+        let mut worker = fake_worker(&coordinator, "only");
+        let stale = start_frame(&coordinator, WORKER_QUEUE_DEPTH as u32 + 1);
+        let mut held = Vec::new();
+        for _ in 0..WORKER_QUEUE_DEPTH {
+            held.push(next_strip(&mut worker).await);
+        }
+
+        // The worker is told to drop the frame, but its queue only goes to the
+        // next frame as it acknowledges each abandoned tile
+        coordinator.cancel_frames(1, None);
+        assert!(matches!(stale.await.unwrap(), Err(FrameError::Cancelled(_))));
+        assert!(matches!(worker.recv().await, Some(CoordinatorToWorker::CancelFrame { .. })));
+        let next = start_frame(&coordinator, 1);
+        while coordinator.pending_frames.read().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert!(worker.try_recv().is_err());
+        for strip in &held {
+            coordinator.handle_strip_abandoned("only", (strip.frame_id, (strip.x_start, strip.y_start))).await;
+        }
+        let strip = next_strip(&mut worker).await;
+        coordinator.handle_strip_result(strip_result("only", &strip)).await;
+        assert!(next.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queued_frame_fails_when_last_worker_leaves() {
+        let coordinator = Coordinator::new();
+        let mut worker = fake_worker(&coordinator, "only");
+        let started = start_frame(&coordinator, WORKER_QUEUE_DEPTH as u32);
+        for _ in 0..WORKER_QUEUE_DEPTH {
+            next_strip(&mut worker).await;
+        }
+        let queued = start_frame(&coordinator, 1);
+        while coordinator.pending_frames.read().unwrap().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // The queued frame can never start; the started one waits for its timeout
+        coordinator.remove_worker("only").await;
+        match queued.await.unwrap() {
+            Err(FrameError::Failed(message)) => assert_eq!(message, "No workers available"),
+            _ => panic!("queued frame should fail"),
+        }
+        assert!(!started.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_failing_tile_is_retried_once() {
+        let coordinator = Coordinator::new();
+        let mut first = fake_worker(&coordinator, "first");
+        let mut second = fake_worker(&coordinator, "second");
+        let frame = start_frame(&coordinator, 1);
+
+        // A tile that fails goes to another worker, and failing again fails the frame
+        let (failed, strip, retry, mut other) = tokio::select! {
+            strip = next_strip(&mut first) => ("first", strip, "second", second),
+            strip = next_strip(&mut second) => ("second", strip, "first", first),
+        };
+        let unit = (strip.frame_id, (strip.x_start, strip.y_start));
+        coordinator.handle_strip_failed(failed, unit, "panicked").await;
+        let retried = next_strip(&mut other).await;
+        assert_eq!((retried.x_start, retried.y_start), unit.1);
+        coordinator.handle_strip_failed(retry, unit, "panicked").await;
+        assert!(matches!(frame.await.unwrap(), Err(FrameError::Failed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_lost_worker_tiles_are_requeued() {
+        let coordinator = Coordinator::new();
+        let mut lost = fake_worker(&coordinator, "lost");
+        let mut healthy = fake_worker(&coordinator, "healthy");
+        let frame = start_frame(&coordinator, 2);
+
+        // One tile each; the first worker then disappears with its tile unfinished
+        let abandoned = next_strip(&mut lost).await;
+        let kept = next_strip(&mut healthy).await;
+        coordinator.workers.write().unwrap().remove("lost");
+        coordinator.requeue_strips().await;
+        let requeued = next_strip(&mut healthy).await;
+        assert_eq!((requeued.x_start, requeued.y_start), (abandoned.x_start, abandoned.y_start));
+
+        coordinator.handle_strip_result(strip_result("healthy", &kept)).await;
+        coordinator.handle_strip_result(strip_result("healthy", &requeued)).await;
+        assert!(frame.await.unwrap().is_ok());
+        assert!(coordinator.workers.read().unwrap()["healthy"].assigned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_worker_tiles_go_elsewhere() {
+        let coordinator = Coordinator::new();
+        let mut stuck = fake_worker(&coordinator, "stuck");
+        let frame = start_frame(&coordinator, 1);
+        let strip = next_strip(&mut stuck).await;
+
+        // The tile has waited past the timeout on a worker that has gone quiet,
+        // and it goes to the other worker even though the first still has room
+        let mut other = fake_worker(&coordinator, "other");
+        let long_ago = Instant::now() - Duration::from_secs(STRIP_TIMEOUT_SECS + 1);
+        coordinator.workers.write().unwrap().get_mut("stuck").unwrap().last_delivery = long_ago;
+        for frame in coordinator.pending_frames.write().unwrap().values_mut() {
+            frame.owed.values_mut().for_each(|owed| owed.sent_at = long_ago);
+        }
+        coordinator.requeue_strips().await;
+        let requeued = next_strip(&mut other).await;
+        assert_eq!((requeued.x_start, requeued.y_start), (strip.x_start, strip.y_start));
+        assert!(stuck.try_recv().is_err());
+
+        // Results that don't fit the tile they were sent for are ignored
+        let mut empty = strip_result("stuck", &strip);
+        empty.x_end = empty.x_start;
+        empty.data = String::new();
+        coordinator.handle_strip_result(empty).await;
+        let mut short = strip_result("stuck", &strip);
+        short.data = base64::engine::general_purpose::STANDARD.encode([7u8; 3]);
+        coordinator.handle_strip_result(short).await;
+        assert!(coordinator.pending_frames.read().unwrap().values().all(|frame| frame.owed.len() == 1));
+
+        coordinator.handle_strip_result(strip_result("other", &requeued)).await;
+        assert!(frame.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_palettes_travel_with_first_strip() {
+        let coordinator = Coordinator::new();
+        let mut worker = fake_worker(&coordinator, "only");
+        let frame = start_frame(&coordinator, 2);
+
+        // The palette comes with the first tile only, in the same message
+        let mut strips = Vec::new();
+        for expected in [1, 0] {
+            match worker.recv().await.unwrap() {
+                CoordinatorToWorker::RenderStrip { strip, palettes } => {
+                    assert_eq!(palettes.len(), expected);
+                    strips.push(*strip);
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+        for strip in &strips {
+            coordinator.handle_strip_result(strip_result("only", strip)).await;
         }
+        assert!(frame.await.unwrap().is_ok());
     }
 }
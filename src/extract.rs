@@ -0,0 +1,260 @@
+//! Palette extraction - build a gradient from the colours of an image
+//!
+//! Colours are clustered in OKLab, so clusters are perceptually even, and
+//! each cluster centre becomes one evenly spaced stop of the gradient.
+
+use crate::colour::{ColourSpace, ColourStop, Gradient, Interpolation, PixelFormat};
+use serde::Deserialize;
+
+/// Most stops an extracted gradient may have
+pub const MAX_EXTRACTED_STOPS: usize = 64;
+
+/// Pixels sampled from the image; larger images are subsampled evenly
+const MAX_SAMPLES: usize = 16384;
+
+/// Upper bound on k-means refinement rounds
+const KMEANS_ROUNDS: usize = 32;
+
+/// How the image's colours are grouped into stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Clustering {
+    /// Lloyd's k-means, starting from the median-cut clusters
+    #[default]
+    KMeans,
+    /// Repeatedly split the widest box of colours at its median
+    MedianCut,
+}
+
+/// How the extracted colours are ordered along the gradient
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StopOrder {
+    /// Dark to light
+    #[default]
+    Luminance,
+    /// Shortest closed loop through the colours, since palettes wrap
+    ShortestPath,
+}
+
+/// Options for building a gradient from an image
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Extraction {
+    #[serde(default = "default_stops")]
+    pub stops: usize,
+    #[serde(default)]
+    pub method: Clustering,
+    #[serde(default)]
+    pub order: StopOrder,
+}
+
+fn default_stops() -> usize {
+    8
+}
+
+type Lab = [f64; 3];
+
+/// Build a gradient of at most `extraction.stops` colours from image pixels
+///
+/// Images with fewer distinct colours give fewer stops.
+pub fn extract_gradient(pixels: &[[u8; 3]], extraction: &Extraction) -> Result<Gradient, String> {
+    if !(2..=MAX_EXTRACTED_STOPS).contains(&extraction.stops) {
+        return Err(format!("Stops must be between 2 and {}, got {}", MAX_EXTRACTED_STOPS, extraction.stops));
+    }
+    if pixels.is_empty() {
+        return Err("Image has no opaque pixels".to_string());
+    }
+
+    let stride = pixels.len().div_ceil(MAX_SAMPLES);
+    let samples: Vec<Lab> = pixels.iter().step_by(stride).map(|&c| ColourSpace::Oklab.encode(c)).collect();
+
+    let mut centres = match extraction.method {
+        Clustering::MedianCut => median_cut(&samples, extraction.stops),
+        Clustering::KMeans => k_means(&samples, median_cut(&samples, extraction.stops)),
+    };
+    match extraction.order {
+        StopOrder::Luminance => centres.sort_by(|a, b| a[0].total_cmp(&b[0])),
+        StopOrder::ShortestPath => centres = shortest_loop(centres),
+    }
+
+    let count = centres.len();
+    Ok(Gradient {
+        stops: centres
+            .into_iter()
+            .enumerate()
+            .map(|(i, centre)| {
+                let mut colour = [0u8; 3];
+                PixelFormat::Rgb8.write(ColourSpace::Oklab.decode(centre), &mut colour);
+                ColourStop { position: i as f64 / count as f64, colour }
+            })
+            .collect(),
+        interpolation: Interpolation::Linear,
+        space: ColourSpace::Oklab,
+    })
+}
+
+fn distance_sq(a: &Lab, b: &Lab) -> f64 {
+    (0..3).map(|c| (a[c] - b[c]) * (a[c] - b[c])).sum()
+}
+
+fn mean(samples: &[Lab]) -> Lab {
+    let sum = samples.iter().fold([0.0; 3], |sum, s| [sum[0] + s[0], sum[1] + s[1], sum[2] + s[2]]);
+    sum.map(|c| c / samples.len() as f64)
+}
+
+/// Widest channel of a box of colours and its extent
+fn widest_axis(samples: &[Lab]) -> (usize, f64) {
+    (0..3)
+        .map(|axis| {
+            let (min, max) = samples
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), s| (min.min(s[axis]), max.max(s[axis])));
+            (axis, max - min)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+}
+
+/// Heckbert's median cut: split the box with the widest spread at the
+/// median of that channel until there are `k` boxes, then take their means
+fn median_cut(samples: &[Lab], k: usize) -> Vec<Lab> {
+    let mut boxes = vec![samples.to_vec()];
+    while boxes.len() < k {
+        let Some((index, axis)) = boxes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (i, widest_axis(b)))
+            .filter(|(_, (_, extent))| *extent > 0.0)
+            .max_by(|a, b| a.1 .1.total_cmp(&b.1 .1))
+            .map(|(i, (axis, _))| (i, axis))
+        else {
+            // Every box is a single colour
+            break;
+        };
+
+        let mut splitting = boxes.swap_remove(index);
+        splitting.sort_by(|a, b| a[axis].total_cmp(&b[axis]));
+        let upper = splitting.split_off(splitting.len() / 2);
+        boxes.push(splitting);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| mean(b)).collect()
+}
+
+/// Refine cluster centres with Lloyd's algorithm; empty clusters are dropped
+fn k_means(samples: &[Lab], mut centres: Vec<Lab>) -> Vec<Lab> {
+    let mut assignment = vec![usize::MAX; samples.len()];
+    for _ in 0..KMEANS_ROUNDS {
+        let mut changed = false;
+        for (sample, assigned) in samples.iter().zip(&mut assignment) {
+            let nearest = (0..centres.len())
+                .min_by(|&a, &b| distance_sq(sample, &centres[a]).total_cmp(&distance_sq(sample, &centres[b])))
+                .unwrap();
+            changed |= *assigned != nearest;
+            *assigned = nearest;
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![([0.0; 3], 0usize); centres.len()];
+        for (sample, &cluster) in samples.iter().zip(&assignment) {
+            let (sum, count) = &mut sums[cluster];
+            (0..3).for_each(|c| sum[c] += sample[c]);
+            *count += 1;
+        }
+        for (centre, (sum, count)) in centres.iter_mut().zip(&sums) {
+            if *count > 0 {
+                *centre = sum.map(|c| c / *count as f64);
+            }
+        }
+    }
+
+    let used: Vec<bool> = (0..centres.len()).map(|i| assignment.contains(&i)).collect();
+    centres.into_iter().zip(used).filter(|(_, used)| *used).map(|(c, _)| c).collect()
+}
+
+/// Order colours as a short closed loop: nearest neighbour from the darkest
+/// colour, then 2-opt until no reversal shortens it
+fn shortest_loop(colours: Vec<Lab>) -> Vec<Lab> {
+    let n = colours.len();
+    if n < 4 {
+        return colours;
+    }
+    let distance = |a: usize, b: usize| distance_sq(&colours[a], &colours[b]).sqrt();
+
+    let darkest = (0..n).min_by(|&a, &b| colours[a][0].total_cmp(&colours[b][0])).unwrap();
+    let mut tour = vec![darkest];
+    let mut remaining: Vec<usize> = (0..n).filter(|&i| i != darkest).collect();
+    while !remaining.is_empty() {
+        let last = *tour.last().unwrap();
+        let (index, _) = remaining
+            .iter()
+            .enumerate()
+            .min_by(|a, b| distance(last, *a.1).total_cmp(&distance(last, *b.1)))
+            .unwrap();
+        tour.push(remaining.swap_remove(index));
+    }
+
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 0..n - 1 {
+            for j in i + 2..n {
+                let (a, b, c, d) = (tour[i], tour[i + 1], tour[j], tour[(j + 1) % n]);
+                if a == d {
+                    continue;
+                }
+                if distance(a, c) + distance(b, d) < distance(a, b) + distance(c, d) - 1e-12 {
+                    tour[i + 1..=j].reverse();
+                    improved = true;
+                }
+            }
+        }
+    }
+
+    tour.into_iter().map(|i| colours[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image made of equal blocks of the given colours
+    fn blocks(colours: &[[u8; 3]]) -> Vec<[u8; 3]> {
+        colours.iter().flat_map(|&c| std::iter::repeat_n(c, 500)).collect()
+    }
+
+    #[test]
+    fn test_extracts_distinct_colours() {
+        let colours = [[200, 30, 30], [20, 20, 20], [240, 240, 200], [30, 60, 200]];
+        let pixels = blocks(&colours);
+        for method in [Clustering::KMeans, Clustering::MedianCut] {
+            let extraction = Extraction { stops: 4, method, order: StopOrder::Luminance };
+            let gradient = extract_gradient(&pixels, &extraction).unwrap();
+            let found: Vec<[u8; 3]> = gradient.stops.iter().map(|s| s.colour).collect();
+            // Dark to light, each within rounding of an input colour
+            assert_eq!(found[0], [20, 20, 20], "{:?}", method);
+            assert_eq!(found[3], [240, 240, 200], "{:?}", method);
+            for colour in &found {
+                assert!(colours.iter().any(|c| (0..3).all(|i| c[i].abs_diff(colour[i]) <= 1)), "{:?}", colour);
+            }
+            assert_eq!(gradient.stops[2].position, 0.5);
+        }
+
+        // Fewer distinct colours than stops gives fewer stops
+        let extraction = Extraction { stops: 8, method: Clustering::KMeans, order: StopOrder::Luminance };
+        assert_eq!(extract_gradient(&blocks(&colours[..2]), &extraction).unwrap().stops.len(), 2);
+        assert!(extract_gradient(&pixels, &Extraction { stops: 1, ..extraction }).is_err());
+    }
+
+    #[test]
+    fn test_shortest_loop_avoids_crossings() {
+        // Corners of a square given in crossing order
+        let corners = vec![[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let tour = shortest_loop(corners);
+        let length: f64 = (0..4).map(|i| distance_sq(&tour[i], &tour[(i + 1) % 4]).sqrt()).sum();
+        assert!((length - 4.0).abs() < 1e-9);
+        assert_eq!(tour[0], [0.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,634 @@
+//! User-defined iteration formulas
+//!
+//! A small complex-number expression language, e.g. `z = z^3 + c*sin(z)`.
+//! Formulas are parsed and validated on the coordinator, compiled to a
+//! compact stack bytecode, and shipped to workers, which run it with a tight
+//! interpreter. Parse errors carry the character range of the offending text.
+//!
+//! Grammar:
+//!
+//! ```text
+//! formula := ["z" "="] expr
+//! expr    := term (("+" | "-") term)*
+//! term    := unary (("*" | "/") unary)*
+//! unary   := "-" unary | power
+//! power   := primary ["^" unary]
+//! primary := number | "z" | "c" | "i" | "pi" | "e"
+//!          | function "(" expr ")" | "(" expr ")"
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Deepest evaluation stack a program may need
+pub const MAX_STACK: usize = 32;
+
+/// Scratch space for `Program::eval`
+pub type Stack = [Complex; MAX_STACK];
+
+/// Longest formula accepted, in bytes
+const MAX_SOURCE_LEN: usize = 1024;
+
+/// Minimal complex number for the interpreter
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub const fn new(re: f64, im: f64) -> Self {
+        Self { re, im }
+    }
+
+    #[inline]
+    pub fn norm_sqr(self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+
+    #[inline]
+    fn mul(self, o: Complex) -> Complex {
+        Complex::new(self.re * o.re - self.im * o.im, self.re * o.im + self.im * o.re)
+    }
+
+    #[inline]
+    fn div(self, o: Complex) -> Complex {
+        let d = o.norm_sqr();
+        Complex::new(
+            (self.re * o.re + self.im * o.im) / d,
+            (self.im * o.re - self.re * o.im) / d,
+        )
+    }
+
+    fn exp(self) -> Complex {
+        let r = self.re.exp();
+        let (sin, cos) = self.im.sin_cos();
+        Complex::new(r * cos, r * sin)
+    }
+
+    fn ln(self) -> Complex {
+        Complex::new(self.norm_sqr().ln() / 2.0, self.im.atan2(self.re))
+    }
+
+    fn powi(self, n: i32) -> Complex {
+        let mut base = if n < 0 { Complex::new(1.0, 0.0).div(self) } else { self };
+        let mut exp = n.unsigned_abs();
+        let mut acc = Complex::new(1.0, 0.0);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                acc = acc.mul(base);
+            }
+            base = base.mul(base);
+            exp >>= 1;
+        }
+        acc
+    }
+
+    fn pow(self, o: Complex) -> Complex {
+        if self.re == 0.0 && self.im == 0.0 {
+            return Complex::default();
+        }
+        self.ln().mul(o).exp()
+    }
+}
+
+/// Built-in functions of one complex argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Sinh,
+    Cosh,
+    Tanh,
+    Exp,
+    Log,
+    Sqrt,
+    Abs,
+    Conj,
+    Re,
+    Im,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "sinh" => Function::Sinh,
+            "cosh" => Function::Cosh,
+            "tanh" => Function::Tanh,
+            "exp" => Function::Exp,
+            "log" | "ln" => Function::Log,
+            "sqrt" => Function::Sqrt,
+            "abs" => Function::Abs,
+            "conj" => Function::Conj,
+            "re" => Function::Re,
+            "im" => Function::Im,
+            _ => return None,
+        })
+    }
+
+    #[inline]
+    fn apply(self, a: Complex) -> Complex {
+        match self {
+            Function::Sin => Complex::new(a.re.sin() * a.im.cosh(), a.re.cos() * a.im.sinh()),
+            Function::Cos => Complex::new(a.re.cos() * a.im.cosh(), -a.re.sin() * a.im.sinh()),
+            Function::Tan => Function::Sin.apply(a).div(Function::Cos.apply(a)),
+            Function::Sinh => Complex::new(a.re.sinh() * a.im.cos(), a.re.cosh() * a.im.sin()),
+            Function::Cosh => Complex::new(a.re.cosh() * a.im.cos(), a.re.sinh() * a.im.sin()),
+            Function::Tanh => Function::Sinh.apply(a).div(Function::Cosh.apply(a)),
+            Function::Exp => a.exp(),
+            Function::Log => a.ln(),
+            Function::Sqrt => {
+                let r = a.norm_sqr().sqrt();
+                let re = ((r + a.re) / 2.0).sqrt();
+                let im = ((r - a.re) / 2.0).sqrt();
+                Complex::new(re, if a.im < 0.0 { -im } else { im })
+            }
+            Function::Abs => Complex::new(a.re.abs(), a.im.abs()),
+            Function::Conj => Complex::new(a.re, -a.im),
+            Function::Re => Complex::new(a.re, 0.0),
+            Function::Im => Complex::new(a.im, 0.0),
+        }
+    }
+}
+
+/// Stack machine instruction
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    /// Push the current z
+    Z,
+    /// Push the pixel's c
+    C,
+    /// Push a constant
+    Const(Complex),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Neg,
+    /// Raise to a complex power
+    Pow,
+    /// Raise to an integer power (repeated squaring)
+    PowInt(i32),
+    Call(Function),
+}
+
+/// Compiled formula, ready to ship to workers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Program {
+    ops: Vec<Op>,
+}
+
+impl Program {
+    /// Parse and compile a formula
+    pub fn compile(source: &str) -> Result<Program, FormulaError> {
+        Self::compile_source(source).map_err(|err| err.in_chars(source))
+    }
+
+    fn compile_source(source: &str) -> Result<Program, FormulaError> {
+        if source.len() > MAX_SOURCE_LEN {
+            return Err(FormulaError::new(
+                format!("Formula is longer than {} characters", MAX_SOURCE_LEN),
+                MAX_SOURCE_LEN..source.len(),
+            ));
+        }
+
+        let tokens = tokenise(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+            ops: Vec::new(),
+            depth: 0,
+            max_depth: 0,
+            source_len: source.len(),
+        };
+        parser.formula()?;
+        Ok(Program { ops: parser.ops })
+    }
+
+    /// Check a received program is well formed: every op has its operands
+    /// and it leaves exactly one value within `MAX_STACK`
+    pub fn validate(&self) -> bool {
+        let mut depth = 0usize;
+        for op in &self.ops {
+            let (pops, pushes) = op.stack_effect();
+            if depth < pops {
+                return false;
+            }
+            depth = depth - pops + pushes;
+            if depth > MAX_STACK {
+                return false;
+            }
+        }
+        depth == 1
+    }
+
+    /// Evaluate one iteration: the new z given the current z and c
+    ///
+    /// The program must have passed `validate`. `stack` is scratch space,
+    /// kept by the caller across a point's iterations.
+    #[inline]
+    pub fn eval(&self, z: Complex, c: Complex, stack: &mut Stack) -> Complex {
+        let mut sp = 0usize;
+
+        for op in &self.ops {
+            match *op {
+                Op::Z => {
+                    stack[sp] = z;
+                    sp += 1;
+                }
+                Op::C => {
+                    stack[sp] = c;
+                    sp += 1;
+                }
+                Op::Const(k) => {
+                    stack[sp] = k;
+                    sp += 1;
+                }
+                Op::Neg => {
+                    let a = stack[sp - 1];
+                    stack[sp - 1] = Complex::new(-a.re, -a.im);
+                }
+                Op::PowInt(n) => stack[sp - 1] = stack[sp - 1].powi(n),
+                Op::Call(f) => stack[sp - 1] = f.apply(stack[sp - 1]),
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => {
+                    sp -= 1;
+                    let (a, b) = (stack[sp - 1], stack[sp]);
+                    stack[sp - 1] = match *op {
+                        Op::Add => Complex::new(a.re + b.re, a.im + b.im),
+                        Op::Sub => Complex::new(a.re - b.re, a.im - b.im),
+                        Op::Mul => a.mul(b),
+                        Op::Div => a.div(b),
+                        _ => a.pow(b),
+                    };
+                }
+            }
+        }
+
+        stack[0]
+    }
+}
+
+impl Op {
+    /// Values popped and pushed
+    fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Op::Z | Op::C | Op::Const(_) => (0, 1),
+            Op::Neg | Op::PowInt(_) | Op::Call(_) => (1, 1),
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => (2, 1),
+        }
+    }
+}
+
+/// Formula parse error, with the character range of the offending text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormulaError {
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl FormulaError {
+    fn new(message: impl Into<String>, span: std::ops::Range<usize>) -> Self {
+        Self {
+            message: message.into(),
+            start: span.start,
+            end: span.end,
+        }
+    }
+
+    /// Convert the range from byte offsets to character offsets, as clients
+    /// index the formula text by character
+    fn in_chars(self, source: &str) -> Self {
+        let chars = |offset: usize| source.char_indices().take_while(|&(i, _)| i < offset).count();
+        Self {
+            start: chars(self.start),
+            end: chars(self.end),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for FormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}..{}", self.message, self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Number(f64),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    LParen,
+    RParen,
+    Equals,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
+
+fn tokenise(source: &str) -> Result<Vec<Token>, FormulaError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let ch = bytes[i];
+        let start = i;
+
+        if ch.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let kind = if ch.is_ascii_digit() || ch == b'.' {
+            while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                i += 1;
+            }
+            // Optional exponent, e.g. 1e-3
+            if i < bytes.len() && (bytes[i] == b'e' || bytes[i] == b'E') {
+                let mut j = i + 1;
+                if j < bytes.len() && (bytes[j] == b'+' || bytes[j] == b'-') {
+                    j += 1;
+                }
+                if j < bytes.len() && bytes[j].is_ascii_digit() {
+                    i = j;
+                    while i < bytes.len() && bytes[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text = &source[start..i];
+            match text.parse::<f64>() {
+                Ok(v) => TokenKind::Number(v),
+                Err(_) => return Err(FormulaError::new(format!("Invalid number '{}'", text), start..i)),
+            }
+        } else if ch.is_ascii_alphabetic() || ch == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            TokenKind::Ident(source[start..i].to_ascii_lowercase())
+        } else {
+            i += 1;
+            match ch {
+                b'+' => TokenKind::Plus,
+                b'-' => TokenKind::Minus,
+                b'*' => TokenKind::Star,
+                b'/' => TokenKind::Slash,
+                b'^' => TokenKind::Caret,
+                b'(' => TokenKind::LParen,
+                b')' => TokenKind::RParen,
+                b'=' => TokenKind::Equals,
+                _ => {
+                    // Report the whole (possibly multi-byte) character
+                    let len = source[start..].chars().next().map_or(1, char::len_utf8);
+                    return Err(FormulaError::new(
+                        format!("Unexpected character '{}'", &source[start..start + len]),
+                        start..start + len,
+                    ));
+                }
+            }
+        };
+
+        tokens.push(Token { kind, start, end: i });
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive-descent parser that emits bytecode as it goes
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    ops: Vec<Op>,
+    depth: usize,
+    max_depth: usize,
+    source_len: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eof_error(&self, message: &str) -> FormulaError {
+        FormulaError::new(message, self.source_len..self.source_len)
+    }
+
+    fn emit(&mut self, op: Op) -> Result<(), FormulaError> {
+        let (pops, pushes) = op.stack_effect();
+        self.depth = self.depth - pops + pushes;
+        self.max_depth = self.max_depth.max(self.depth);
+        self.ops.push(op);
+        if self.max_depth > MAX_STACK {
+            let span = self.tokens.get(self.pos.saturating_sub(1)).map_or(0..0, |t| t.start..t.end);
+            return Err(FormulaError::new("Formula is nested too deeply", span));
+        }
+        Ok(())
+    }
+
+    fn formula(&mut self) -> Result<(), FormulaError> {
+        // Optional "z =" prefix
+        if let (Some(Token { kind: TokenKind::Ident(name), .. }), Some(Token { kind: TokenKind::Equals, .. })) =
+            (self.tokens.first(), self.tokens.get(1))
+        {
+            if name == "z" {
+                self.pos = 2;
+            }
+        }
+
+        if self.peek().is_none() {
+            return Err(self.eof_error("Formula is empty"));
+        }
+
+        self.expr()?;
+
+        if let Some(token) = self.peek() {
+            return Err(FormulaError::new("Unexpected input after formula", token.start..token.end));
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<(), FormulaError> {
+        self.term()?;
+        while let Some(op) = self.peek().and_then(|t| match t.kind {
+            TokenKind::Plus => Some(Op::Add),
+            TokenKind::Minus => Some(Op::Sub),
+            _ => None,
+        }) {
+            self.pos += 1;
+            self.term()?;
+            self.emit(op)?;
+        }
+        Ok(())
+    }
+
+    fn term(&mut self) -> Result<(), FormulaError> {
+        self.unary()?;
+        while let Some(op) = self.peek().and_then(|t| match t.kind {
+            TokenKind::Star => Some(Op::Mul),
+            TokenKind::Slash => Some(Op::Div),
+            _ => None,
+        }) {
+            self.pos += 1;
+            self.unary()?;
+            self.emit(op)?;
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self) -> Result<(), FormulaError> {
+        if matches!(self.peek(), Some(Token { kind: TokenKind::Minus, .. })) {
+            self.pos += 1;
+            self.unary()?;
+            return self.emit(Op::Neg);
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<(), FormulaError> {
+        self.primary()?;
+        if !matches!(self.peek(), Some(Token { kind: TokenKind::Caret, .. })) {
+            return Ok(());
+        }
+        self.pos += 1;
+
+        // Small integer exponents get the fast repeated-squaring op
+        if let Some(Token { kind: TokenKind::Number(n), .. }) = self.peek() {
+            let n = *n;
+            let next_is_operand_end = !matches!(
+                self.tokens.get(self.pos + 1),
+                Some(Token { kind: TokenKind::Caret, .. })
+            );
+            if n.fract() == 0.0 && n.abs() <= 64.0 && next_is_operand_end {
+                self.pos += 1;
+                return self.emit(Op::PowInt(n as i32));
+            }
+        }
+
+        self.unary()?;
+        self.emit(Op::Pow)
+    }
+
+    fn primary(&mut self) -> Result<(), FormulaError> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.eof_error("Expected a value"));
+        };
+        let span = token.start..token.end;
+        self.pos += 1;
+
+        match token.kind {
+            TokenKind::Number(v) => self.emit(Op::Const(Complex::new(v, 0.0))),
+            TokenKind::LParen => {
+                self.expr()?;
+                self.expect_rparen(span)
+            }
+            TokenKind::Ident(name) => match name.as_str() {
+                "z" => self.emit(Op::Z),
+                "c" => self.emit(Op::C),
+                "i" => self.emit(Op::Const(Complex::new(0.0, 1.0))),
+                "pi" => self.emit(Op::Const(Complex::new(std::f64::consts::PI, 0.0))),
+                "e" => self.emit(Op::Const(Complex::new(std::f64::consts::E, 0.0))),
+                _ => {
+                    let Some(function) = Function::from_name(&name) else {
+                        return Err(FormulaError::new(format!("Unknown name '{}'", name), span));
+                    };
+                    match self.peek() {
+                        Some(Token { kind: TokenKind::LParen, start, end }) => {
+                            let paren = *start..*end;
+                            self.pos += 1;
+                            self.expr()?;
+                            self.expect_rparen(paren)?;
+                            self.emit(Op::Call(function))
+                        }
+                        _ => Err(FormulaError::new(format!("Expected '(' after '{}'", name), span)),
+                    }
+                }
+            },
+            _ => Err(FormulaError::new("Expected a value", span)),
+        }
+    }
+
+    fn expect_rparen(&mut self, open: std::ops::Range<usize>) -> Result<(), FormulaError> {
+        match self.peek() {
+            Some(Token { kind: TokenKind::RParen, .. }) => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(token) => Err(FormulaError::new("Expected ')'", token.start..token.end)),
+            None => Err(FormulaError::new("Unclosed '('", open)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str, z: Complex, c: Complex) -> Complex {
+        let program = Program::compile(source).unwrap();
+        assert!(program.validate());
+        program.eval(z, c, &mut [Complex::default(); MAX_STACK])
+    }
+
+    #[test]
+    fn test_mandelbrot_formula() {
+        let z = Complex::new(0.5, -1.5);
+        let c = Complex::new(-0.2, 0.7);
+        let expected = z.mul(z);
+        let result = eval("z = z^2 + c", z, c);
+        assert!((result.re - (expected.re + c.re)).abs() < 1e-12);
+        assert!((result.im - (expected.im + c.im)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_precedence_and_functions() {
+        let one = Complex::new(1.0, 0.0);
+        assert_eq!(eval("2 + 3 * 4", one, one), Complex::new(14.0, 0.0));
+        assert_eq!(eval("-2^2", one, one), Complex::new(-4.0, 0.0));
+        assert_eq!(eval("i * i", one, one), Complex::new(-1.0, 0.0));
+
+        let r = eval("z^3 + c*sin(z)", Complex::new(0.3, 0.2), Complex::new(0.1, 0.1));
+        assert!(r.re.is_finite() && r.im.is_finite());
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let err = Program::compile("z^2 + foo(z)").unwrap_err();
+        assert_eq!((err.start, err.end), (6, 9));
+
+        let err = Program::compile("z^2 + (c").unwrap_err();
+        assert_eq!(err.message, "Unclosed '('");
+        assert_eq!((err.start, err.end), (6, 7));
+
+        let err = Program::compile("z^2 $ c").unwrap_err();
+        assert_eq!((err.start, err.end), (4, 5));
+
+        // Positions count characters, not bytes
+        let err = Program::compile("z^2 + π").unwrap_err();
+        assert_eq!((err.start, err.end), (6, 7));
+
+        let err = Program::compile("z = ").unwrap_err();
+        assert_eq!(err.message, "Formula is empty");
+    }
+
+    #[test]
+    fn test_validate_rejects_malformed() {
+        let program = Program { ops: vec![Op::Z, Op::Add] };
+        assert!(!program.validate());
+    }
+}
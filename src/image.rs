@@ -0,0 +1,150 @@
+//! Image file encoding and decoding for the HTTP endpoints
+
+/// Largest image accepted for decoding, in pixels
+const MAX_IMAGE_PIXELS: u64 = 4096 * 4096;
+
+/// Encode 8-bit RGB pixels, row-major, as a PNG file
+pub fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(rgb).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+/// Decode a PPM (`P3` or `P6`) or PNG file into its 8-bit RGB pixels
+///
+/// Fully transparent PNG pixels are left out.
+pub fn decode_rgb(bytes: &[u8]) -> Result<Vec<[u8; 3]>, String> {
+    if bytes.starts_with(b"\x89PNG") {
+        decode_png(bytes)
+    } else if bytes.starts_with(b"P3") || bytes.starts_with(b"P6") {
+        decode_ppm(bytes)
+    } else {
+        Err("Expected a PNG or PPM (P3/P6) image".to_string())
+    }
+}
+
+fn decode_png(bytes: &[u8]) -> Result<Vec<[u8; 3]>, String> {
+    let mut decoder = png::Decoder::new(bytes);
+    // Expand palettes and low bit depths, and drop 16-bit precision
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let (width, height) = reader.info().size();
+    check_size(width, height)?;
+
+    let mut buffer = vec![0u8; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+    let data = &buffer[..frame.buffer_size()];
+
+    Ok(match frame.color_type {
+        png::ColorType::Rgb => data.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect(),
+        png::ColorType::Rgba => data.chunks_exact(4).filter(|p| p[3] > 0).map(|p| [p[0], p[1], p[2]]).collect(),
+        png::ColorType::Grayscale => data.iter().map(|&v| [v; 3]).collect(),
+        png::ColorType::GrayscaleAlpha => data.chunks_exact(2).filter(|p| p[1] > 0).map(|p| [p[0]; 3]).collect(),
+        png::ColorType::Indexed => return Err("Unexpanded indexed PNG".to_string()),
+    })
+}
+
+/// Decode a binary (`P6`) or ASCII (`P3`) PPM, scaling samples to 8 bits
+fn decode_ppm(bytes: &[u8]) -> Result<Vec<[u8; 3]>, String> {
+    // Header: magic, width, height and maximum value, with `#` comments
+    let mut pos = 0;
+    let mut fields = Vec::new();
+    while fields.len() < 4 {
+        while pos < bytes.len() && (bytes[pos].is_ascii_whitespace() || bytes[pos] == b'#') {
+            if bytes[pos] == b'#' {
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    pos += 1;
+                }
+            } else {
+                pos += 1;
+            }
+        }
+        let start = pos;
+        while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err("Truncated PPM header".to_string());
+        }
+        fields.push(String::from_utf8_lossy(&bytes[start..pos]).into_owned());
+    }
+
+    let number = |field: &str| field.parse::<u32>().map_err(|_| format!("Bad PPM header value '{}'", field));
+    let (width, height, max_value) = (number(&fields[1])?, number(&fields[2])?, number(&fields[3])?);
+    if max_value == 0 || max_value > 65535 {
+        return Err(format!("PPM maximum value {} is outside 1..65535", max_value));
+    }
+    check_size(width, height)?;
+    let count = width as usize * height as usize * 3;
+    let scale = |v: u32| ((v.min(max_value) * 255 + max_value / 2) / max_value) as u8;
+
+    let samples: Vec<u32> = if fields[0] == "P6" {
+        // A single whitespace byte separates the header from the raster
+        let raster = bytes.get(pos + 1..).unwrap_or_default();
+        if max_value < 256 {
+            raster.iter().take(count).map(|&v| v as u32).collect()
+        } else {
+            raster.chunks_exact(2).take(count).map(|v| u16::from_be_bytes([v[0], v[1]]) as u32).collect()
+        }
+    } else {
+        String::from_utf8_lossy(&bytes[pos..])
+            .split_ascii_whitespace()
+            .take(count)
+            .map(number)
+            .collect::<Result<_, _>>()?
+    };
+    if samples.len() < count {
+        return Err("PPM raster is shorter than its header says".to_string());
+    }
+
+    Ok(samples.chunks_exact(3).map(|p| [scale(p[0]), scale(p[1]), scale(p[2])]).collect())
+}
+
+fn check_size(width: u32, height: u32) -> Result<(), String> {
+    if width as u64 * height as u64 > MAX_IMAGE_PIXELS {
+        return Err(format!("Image is larger than {} pixels", MAX_IMAGE_PIXELS));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_round_trip() {
+        let rgb: Vec<u8> = (0..4 * 2 * 3).map(|i| i as u8 * 10).collect();
+        let png = encode_png(4, 2, &rgb).unwrap();
+
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut decoded = vec![0u8; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut decoded).unwrap();
+        assert_eq!((info.width, info.height), (4, 2));
+        assert_eq!(decoded, rgb);
+        assert_eq!(decode_rgb(&png).unwrap(), rgb.chunks(3).map(|p| [p[0], p[1], p[2]]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_decode_ppm() {
+        let expected = vec![[255, 0, 0], [0, 128, 255]];
+        let ascii = b"P3\n# two pixels\n2 1\n255\n255 0 0  0 128 255\n";
+        assert_eq!(decode_rgb(ascii).unwrap(), expected);
+
+        let mut binary = b"P6 2 1 255\n".to_vec();
+        binary.extend([255, 0, 0, 0, 128, 255]);
+        assert_eq!(decode_rgb(&binary).unwrap(), expected);
+
+        // 16-bit samples are big-endian and scaled down
+        let mut deep = b"P6\n2 1\n65535\n".to_vec();
+        deep.extend([255, 255, 0, 0, 0, 0, 0, 0, 128, 128, 255, 255]);
+        assert_eq!(decode_rgb(&deep).unwrap(), expected);
+
+        assert!(decode_rgb(b"P6 2 1 255\n\x01\x02").is_err());
+        assert!(decode_rgb(b"GIF89a").is_err());
+    }
+}
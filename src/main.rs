@@ -1,25 +1,36 @@
 mod colour;
 mod coordinator;
+mod extract;
+mod formula;
+mod image;
 mod mandelbrot;
 mod messages;
+mod palette_cache;
+mod reproject;
+mod scheduler;
 mod worker;
 
 use axum::{
     extract::{
         ws::{WebSocket, WebSocketUpgrade},
-        State,
+        DefaultBodyLimit, Path, Query, State,
     },
+    body::Bytes,
+    http::{header, StatusCode},
     response::IntoResponse,
-    routing::get,
-    Router,
+    routing::{get, post},
+    Json, Router,
 };
+use serde::Deserialize;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use colour::{ColourVision, Palette};
 use coordinator::Coordinator;
+use extract::Extraction;
 use worker::Worker;
 
 #[derive(Clone, Copy, PartialEq)]
@@ -67,24 +78,13 @@ async fn run_coordinator() {
     tracing::info!("Starting in COORDINATOR mode");
 
     let coordinator = Coordinator::new();
+    coordinator.load_palette_dir(&palette_dir());
 
     // Start the profiling loop
     coordinator.start_profile_loop();
+    coordinator.start_strip_watchdog();
 
-    // CORS configuration
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-
-    // Build the router
-    let app = Router::new()
-        .route("/ws/worker", get(worker_ws_handler))
-        .route("/ws/client", get(client_ws_handler))
-        .route("/health", get(health_handler))
-        .nest_service("/", ServeDir::new("static").append_index_html_on_directories(true))
-        .layer(cors)
-        .with_state(coordinator);
+    let app = router(coordinator);
 
     // Get port from environment or default to 8080
     let port: u16 = std::env::var("PORT")
@@ -99,6 +99,35 @@ async fn run_coordinator() {
     axum::serve(listener, app).await.unwrap();
 }
 
+/// Routes served by the coordinator
+fn router(coordinator: Arc<Coordinator>) -> Router {
+    // CORS configuration
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods(Any)
+        .allow_headers(Any);
+
+    Router::new()
+        .route("/ws/worker", get(worker_ws_handler))
+        .route("/ws/client", get(client_ws_handler))
+        .route("/health", get(health_handler))
+        .route("/palettes", get(list_palettes_handler))
+        .route("/palettes/preview", get(palette_preview_handler))
+        .route("/palettes/:file_name", post(upload_palette_handler))
+        .route(
+            "/palettes/extract/:name",
+            post(extract_palette_handler).layer(DefaultBodyLimit::max(MAX_IMAGE_UPLOAD)),
+        )
+        .nest_service("/", ServeDir::new("static").append_index_html_on_directories(true))
+        .layer(cors)
+        .with_state(coordinator)
+}
+
+/// Directory of palette files loaded at startup
+fn palette_dir() -> std::path::PathBuf {
+    std::env::var("PALETTE_DIR").unwrap_or_else(|_| "palettes".to_string()).into()
+}
+
 async fn run_worker() {
     let coordinator_url = std::env::var("COORDINATOR_URL")
         .expect("COORDINATOR_URL environment variable required in worker mode");
@@ -123,9 +152,11 @@ async fn run_standalone() {
     tracing::info!("Starting in STANDALONE mode (coordinator + {} local workers)", num_workers);
 
     let coordinator = Coordinator::new();
+    coordinator.load_palette_dir(&palette_dir());
 
     // Start the profiling loop
     coordinator.start_profile_loop();
+    coordinator.start_strip_watchdog();
 
     // Get port from environment or default to 8080
     let port: u16 = std::env::var("PORT")
@@ -145,20 +176,7 @@ async fn run_standalone() {
         });
     }
 
-    // CORS configuration
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-
-    // Build the router
-    let app = Router::new()
-        .route("/ws/worker", get(worker_ws_handler))
-        .route("/ws/client", get(client_ws_handler))
-        .route("/health", get(health_handler))
-        .nest_service("/", ServeDir::new("static").append_index_html_on_directories(true))
-        .layer(cors)
-        .with_state(coordinator);
+    let app = router(coordinator);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     tracing::info!("Standalone server listening on {}", addr);
@@ -187,6 +205,88 @@ async fn client_ws_handler(
     })
 }
 
+/// List the names of imported palettes
+async fn list_palettes_handler(State(coordinator): State<Arc<Coordinator>>) -> Json<Vec<String>> {
+    Json(coordinator.palette_names())
+}
+
+/// Import a palette file; the format is taken from the file name's extension
+async fn upload_palette_handler(
+    Path(file_name): Path<String>,
+    State(coordinator): State<Arc<Coordinator>>,
+    body: String,
+) -> impl IntoResponse {
+    match coordinator.register_palettes(&file_name, &body) {
+        Ok(names) => Ok(Json(names)),
+        Err(message) => Err((StatusCode::BAD_REQUEST, message)),
+    }
+}
+
+/// Largest image accepted for palette extraction, in bytes
+const MAX_IMAGE_UPLOAD: usize = 32 * 1024 * 1024;
+
+/// Build a palette from an uploaded image, e.g. `POST /palettes/extract/sunset?stops=12&order=shortest_path`
+async fn extract_palette_handler(
+    Path(name): Path<String>,
+    Query(extraction): Query<Extraction>,
+    State(coordinator): State<Arc<Coordinator>>,
+    body: Bytes,
+) -> impl IntoResponse {
+    // Decoding and clustering a large image takes a while; keep it off the async workers
+    let result = tokio::task::spawn_blocking(move || coordinator.extract_palette(&name, &body, &extraction))
+        .await
+        .unwrap_or_else(|e| Err(format!("Palette extraction failed: {}", e)));
+    match result {
+        Ok(gradient) => Ok(Json(gradient)),
+        Err(message) => Err((StatusCode::BAD_REQUEST, message)),
+    }
+}
+
+/// Query for a palette preview, e.g. `?palette=fire&vision=deuteranopia`
+#[derive(Deserialize)]
+struct PreviewQuery {
+    /// A preset or imported palette name, or a palette as JSON
+    palette: String,
+    #[serde(default = "default_preview_width")]
+    width: u32,
+    #[serde(default = "default_preview_height")]
+    height: u32,
+    vision: Option<ColourVision>,
+}
+
+fn default_preview_width() -> u32 {
+    512
+}
+
+fn default_preview_height() -> u32 {
+    32
+}
+
+/// Largest preview strip served, in pixels
+const MAX_PREVIEW_WIDTH: u32 = 4096;
+const MAX_PREVIEW_HEIGHT: u32 = 256;
+
+/// Render a palette as a PNG gradient strip
+async fn palette_preview_handler(
+    Query(query): Query<PreviewQuery>,
+    State(coordinator): State<Arc<Coordinator>>,
+) -> impl IntoResponse {
+    // Names are bare strings, so only parse JSON objects as JSON
+    let palette: Result<Palette, _> = if query.palette.trim_start().starts_with('{') {
+        serde_json::from_str(&query.palette)
+    } else {
+        serde_json::from_value(serde_json::Value::String(query.palette))
+    };
+    let palette = palette.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let width = query.width.clamp(1, MAX_PREVIEW_WIDTH);
+    let height = query.height.clamp(1, MAX_PREVIEW_HEIGHT);
+
+    match coordinator.palette_preview(&palette, width, height, query.vision) {
+        Ok(png) => Ok(([(header::CONTENT_TYPE, "image/png")], png)),
+        Err(message) => Err((StatusCode::BAD_REQUEST, message)),
+    }
+}
+
 /// Health check endpoint
 async fn health_handler() -> &'static str {
     "OK"
@@ -1,8 +1,13 @@
-/// Core Mandelbrot set computation
-///
-/// Uses escape-time algorithm with smooth colouring
+//! Core Mandelbrot set computation
+//!
+//! Uses escape-time algorithm with smooth colouring
 
-use crate::colour::colour_interior;
+use crate::colour::{colour_interior, ColourMapping, InteriorMode, Layer, LayerSource, OrbitTrap, Rgb};
+use crate::formula::{Complex, Program, MAX_STACK};
+use crate::messages::{Bailout, BailoutNorm, Formula, Projection, RenderStripRequest, Reprojection};
+use std::f64::consts::TAU;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 /// Result of computing a single Mandelbrot point
 pub struct MandelbrotResult {
@@ -12,25 +17,65 @@ pub struct MandelbrotResult {
     pub final_x: f64,
     /// Final y position of orbit (for interior colouring)
     pub final_y: f64,
+    /// Final derivative dz/dc, real part (for lighting)
+    pub deriv_x: f64,
+    /// Final derivative dz/dc, imaginary part (for lighting)
+    pub deriv_y: f64,
     /// Whether the point is in the set
     pub in_set: bool,
+    /// Closest approach of the orbit to the orbit trap (infinite if untracked)
+    pub trap_distance: f64,
+    /// Mean of the orbit, real part (tracked by the general loops only)
+    pub mean_x: f64,
+    /// Mean of the orbit, imaginary part
+    pub mean_y: f64,
+    /// Period of the attracting cycle, if it was looked for and found
+    pub period: u32,
 }
 
 /// Compute the Mandelbrot iteration count for a single point
 /// Returns smooth iteration count and final orbit position
 #[inline]
-pub fn mandelbrot_point(cx: f64, cy: f64, max_iterations: u32) -> MandelbrotResult {
+pub fn mandelbrot_point(cx: f64, cy: f64, max_iterations: u32, bailout: &Bailout) -> MandelbrotResult {
+    mandelbrot_orbit::<false>(cx, cy, max_iterations, bailout)
+}
+
+/// As `mandelbrot_point`, also tracking the derivative dz/dc for distance
+/// estimates and surface normals
+#[inline]
+pub fn mandelbrot_point_derivative(cx: f64, cy: f64, max_iterations: u32, bailout: &Bailout) -> MandelbrotResult {
+    mandelbrot_orbit::<true>(cx, cy, max_iterations, bailout)
+}
+
+#[inline(always)]
+fn mandelbrot_orbit<const DERIVATIVE: bool>(
+    cx: f64,
+    cy: f64,
+    max_iterations: u32,
+    bailout: &Bailout,
+) -> MandelbrotResult {
     let mut x = 0.0_f64;
     let mut y = 0.0_f64;
     let mut x2 = 0.0_f64;
     let mut y2 = 0.0_f64;
+    let mut dx = 0.0_f64;
+    let mut dy = 0.0_f64;
 
     let mut iteration = 0u32;
 
-    // Escape radius squared (using 256 for smooth colouring)
-    const ESCAPE_RADIUS_SQ: f64 = 65536.0; // 256^2
+    let euclidean = bailout.norm == BailoutNorm::Euclidean;
+    let radius_sq = bailout.radius * bailout.radius;
+
+    while (if euclidean { x2 + y2 <= radius_sq } else { bailout.contains(x, y) })
+        && iteration < max_iterations
+    {
+        if DERIVATIVE {
+            // dz' = 2 * z * dz + 1
+            let new_dx = 2.0 * (x * dx - y * dy) + 1.0;
+            dy = 2.0 * (x * dy + y * dx);
+            dx = new_dx;
+        }
 
-    while x2 + y2 <= ESCAPE_RADIUS_SQ && iteration < max_iterations {
         y = 2.0 * x * y + cy;
         x = x2 - y2 + cx;
         x2 = x * x;
@@ -38,119 +83,774 @@ pub fn mandelbrot_point(cx: f64, cy: f64, max_iterations: u32) -> MandelbrotResu
         iteration += 1;
     }
 
+    finish_point(iteration, max_iterations, x, y, dx, dy, bailout, 2.0)
+}
+
+/// Build the result for an orbit that escaped or ran out of iterations
+///
+/// `degree` is how fast the orbit grows near escape (|z| -> |z|^degree).
+#[inline]
+#[allow(clippy::too_many_arguments)]
+fn finish_point(
+    iteration: u32,
+    max_iterations: u32,
+    x: f64,
+    y: f64,
+    dx: f64,
+    dy: f64,
+    bailout: &Bailout,
+    degree: f64,
+) -> MandelbrotResult {
     if iteration >= max_iterations {
         // Point is in the set
         return MandelbrotResult {
             smooth_iter: max_iterations as f64,
             final_x: x,
             final_y: y,
+            deriv_x: dx,
+            deriv_y: dy,
             in_set: true,
+            trap_distance: f64::INFINITY,
+            mean_x: 0.0,
+            mean_y: 0.0,
+            period: 0,
         };
     }
 
-    // Smooth colouring using normalised iteration count
-    let log_zn = (x2 + y2).ln() / 2.0;
-    let nu = (log_zn / std::f64::consts::LN_2).ln() / std::f64::consts::LN_2;
+    // Smooth colouring using normalised iteration count: how far the final z
+    // went, on a log-log scale, in the same norm that decided it escaped. The
+    // usual |z| keeps the classic log2(log2 |z|) so colours don't shift; the
+    // other norms are measured from the bailout radius, and as the partial
+    // norms (real or imaginary only) can overshoot by more than one step, kept
+    // within [0, 1].
+    let nu = if bailout.norm == BailoutNorm::Euclidean {
+        (bailout.measure(x, y).ln() / std::f64::consts::LN_2).ln() / degree.ln()
+    } else {
+        let ratio = bailout.measure(x, y).ln() / bailout.radius.ln();
+        (ratio.ln() / degree.ln()).clamp(0.0, 1.0)
+    };
+    let nu = if nu.is_finite() { nu } else { 0.0 };
 
     MandelbrotResult {
         smooth_iter: iteration as f64 + 1.0 - nu,
         final_x: x,
         final_y: y,
+        deriv_x: dx,
+        deriv_y: dy,
         in_set: false,
+        trap_distance: f64::INFINITY,
+        mean_x: 0.0,
+        mean_y: 0.0,
+        period: 0,
+    }
+}
+
+/// Compute the iteration count for a hybrid formula
+///
+/// `schedule` lists the formula used on each iteration of one cycle, as
+/// produced by `FormulaSequence::schedule`; it repeats until the orbit
+/// escapes. All formulas are quadratic, so the smooth colouring still holds.
+/// With a `trap`, the orbit's closest approach to it is recorded too.
+/// The orbit's mean is always recorded.
+pub fn hybrid_point(
+    cx: f64,
+    cy: f64,
+    max_iterations: u32,
+    bailout: &Bailout,
+    schedule: &[Formula],
+    trap: Option<&OrbitTrap>,
+) -> MandelbrotResult {
+    let mut x = 0.0_f64;
+    let mut y = 0.0_f64;
+    let mut dx = 0.0_f64;
+    let mut dy = 0.0_f64;
+    let mut trap_distance = f64::INFINITY;
+    let (mut sum_x, mut sum_y) = (0.0_f64, 0.0_f64);
+
+    let mut iteration = 0u32;
+
+    while bailout.contains(x, y) && iteration < max_iterations {
+        let formula = schedule[iteration as usize % schedule.len()];
+        (x, y, dx, dy) = formula_step(formula, x, y, dx, dy, cx, cy);
+        if let Some(trap) = trap {
+            trap_distance = trap_distance.min(trap.distance(x, y));
+        }
+        sum_x += x;
+        sum_y += y;
+        iteration += 1;
+    }
+
+    let count = iteration.max(1) as f64;
+    MandelbrotResult {
+        trap_distance,
+        mean_x: sum_x / count,
+        mean_y: sum_y / count,
+        ..finish_point(iteration, max_iterations, x, y, dx, dy, bailout, 2.0)
     }
 }
 
-/// Render a horizontal strip of the Mandelbrot set
+/// Slowest growth assumed for a user-defined formula when smoothing its count
+pub const MIN_CUSTOM_DEGREE: f64 = 1.05;
+
+/// Compute the iteration count for a user-defined formula
 ///
-/// Returns RGB pixel data as a Vec<u8> (3 bytes per pixel)
-pub fn render_strip(
-    width: u32,
-    y_start: u32,
-    y_end: u32,
-    total_height: u32,
+/// Orbits start at z = c (starting at zero would leave formulas such as
+/// `z^3 + c*sin(z)` stuck at zero). The formula's degree isn't known, so
+/// the smooth colouring estimates it from the last two orbit magnitudes.
+/// No derivative is tracked, so lighting has no effect.
+pub fn custom_point(
+    cx: f64,
+    cy: f64,
+    max_iterations: u32,
+    bailout: &Bailout,
+    program: &Program,
+    trap: Option<&OrbitTrap>,
+) -> MandelbrotResult {
+    let c = Complex::new(cx, cy);
+    let mut z = c;
+    let mut previous_norm = z.norm_sqr();
+    let mut trap_distance = trap.map_or(f64::INFINITY, |trap| trap.distance(z.re, z.im));
+    let (mut sum_x, mut sum_y) = (z.re, z.im);
+
+    let mut iteration = 0u32;
+    let mut stack = [Complex::default(); MAX_STACK];
+
+    while bailout.contains(z.re, z.im) && iteration < max_iterations {
+        previous_norm = z.norm_sqr();
+        z = program.eval(z, c, &mut stack);
+        if let Some(trap) = trap {
+            trap_distance = trap_distance.min(trap.distance(z.re, z.im));
+        }
+        sum_x += z.re;
+        sum_y += z.im;
+        iteration += 1;
+    }
+    let count = (iteration + 1) as f64;
+
+    // Diverged to infinity/NaN in one step; treat as escaping here
+    if !z.norm_sqr().is_finite() {
+        return MandelbrotResult {
+            smooth_iter: iteration as f64,
+            final_x: 0.0,
+            final_y: 0.0,
+            deriv_x: 0.0,
+            deriv_y: 0.0,
+            in_set: false,
+            trap_distance,
+            mean_x: 0.0,
+            mean_y: 0.0,
+            period: 0,
+        };
+    }
+
+    let log_zn = z.norm_sqr().ln() / 2.0;
+    let log_prev = previous_norm.ln() / 2.0;
+    let degree = if log_prev > 0.0 { (log_zn / log_prev).clamp(MIN_CUSTOM_DEGREE, 16.0) } else { 2.0 };
+
+    MandelbrotResult {
+        trap_distance,
+        mean_x: sum_x / count,
+        mean_y: sum_y / count,
+        ..finish_point(iteration, max_iterations, z.re, z.im, 0.0, 0.0, bailout, degree)
+    }
+}
+
+/// Iteration loop selected by a request
+///
+/// Orbit traps and orbit averages need the general loop, so the plain
+/// Mandelbrot fast path is only used without them. The fast path only tracks
+/// the derivative when lighting or a colouring needs it.
+enum Iteration {
+    Mandelbrot,
+    MandelbrotDerivative,
+    Hybrid(Vec<Formula>, Option<OrbitTrap>),
+    Custom(Program, Option<OrbitTrap>),
+}
+
+impl Iteration {
+    fn new(req: &RenderStripRequest) -> Self {
+        let layers = effective_layers(req);
+        let trap = Layer::orbit_trap(&layers);
+        let averages = layers.iter().any(|layer| {
+            layer.source == LayerSource::Interior { mode: InteriorMode::OrbitAverage }
+        });
+        if let Some(program) = &req.program {
+            if program.validate() {
+                return Iteration::Custom(program.clone(), trap);
+            }
+            tracing::warn!("Ignoring malformed formula program in frame {}", req.frame_id);
+        }
+        match (req.formula.schedule(), trap) {
+            (Some(schedule), trap) => Iteration::Hybrid(schedule, trap),
+            (None, trap) if trap.is_some() || averages => Iteration::Hybrid(vec![Formula::Mandelbrot], trap),
+            (None, _) if needs_derivative(req, &layers) => Iteration::MandelbrotDerivative,
+            (None, _) => Iteration::Mandelbrot,
+        }
+    }
+
+    #[inline]
+    fn point(&self, cx: f64, cy: f64, max_iterations: u32, bailout: &Bailout) -> MandelbrotResult {
+        match self {
+            Iteration::Mandelbrot => mandelbrot_point(cx, cy, max_iterations, bailout),
+            Iteration::MandelbrotDerivative => mandelbrot_point_derivative(cx, cy, max_iterations, bailout),
+            Iteration::Hybrid(schedule, trap) => {
+                hybrid_point(cx, cy, max_iterations, bailout, schedule, trap.as_ref())
+            }
+            Iteration::Custom(program, trap) => {
+                custom_point(cx, cy, max_iterations, bailout, program, trap.as_ref())
+            }
+        }
+    }
+
+    /// Period of the cycle an interior orbit has settled into, or 0 if it
+    /// doesn't return close to its final point within `MAX_PERIOD` steps
+    fn period(&self, cx: f64, cy: f64, result: &MandelbrotResult, max_iterations: u32) -> u32 {
+        let (x0, y0) = (result.final_x, result.final_y);
+        let tolerance = PERIOD_TOLERANCE * (1.0 + x0.hypot(y0));
+        let (mut x, mut y) = (x0, y0);
+        let mut stack = [Complex::default(); MAX_STACK];
+
+        for period in 1..=MAX_PERIOD {
+            (x, y) = match self {
+                Iteration::Mandelbrot | Iteration::MandelbrotDerivative => {
+                    let (x, y, _, _) = formula_step(Formula::Mandelbrot, x, y, 0.0, 0.0, cx, cy);
+                    (x, y)
+                }
+                Iteration::Hybrid(schedule, _) => {
+                    // Carry on through the schedule from where the orbit stopped
+                    let formula = schedule[(max_iterations + period - 1) as usize % schedule.len()];
+                    let (x, y, _, _) = formula_step(formula, x, y, 0.0, 0.0, cx, cy);
+                    (x, y)
+                }
+                Iteration::Custom(program, _) => {
+                    let z = program.eval(Complex::new(x, y), Complex::new(cx, cy), &mut stack);
+                    (z.re, z.im)
+                }
+            };
+            if (x - x0).hypot(y - y0) < tolerance {
+                return period;
+            }
+        }
+        0
+    }
+}
+
+/// Whether a strip's lighting or colouring uses the derivative dz/dc
+fn needs_derivative(req: &RenderStripRequest, layers: &[Layer]) -> bool {
+    req.lighting.is_some()
+        || layers.iter().any(|layer| {
+            matches!(
+                layer.source,
+                LayerSource::DistanceEstimate | LayerSource::Interior { mode: InteriorMode::Derivative }
+            )
+        })
+}
+
+/// Longest attracting cycle `InteriorMode::Period` looks for
+const MAX_PERIOD: u32 = 64;
+
+/// How close, relative to |z|, an orbit must return to count as periodic
+const PERIOD_TOLERANCE: f64 = 1e-6;
+
+/// Apply one iteration of `formula` to z = (x, y) and its derivative (dx, dy)
+///
+/// The folding formulas aren't holomorphic; their derivative follows the
+/// same folds (sign flips) as z, which is what lighting needs.
+#[inline]
+fn formula_step(
+    formula: Formula,
+    x: f64,
+    y: f64,
+    dx: f64,
+    dy: f64,
+    cx: f64,
+    cy: f64,
+) -> (f64, f64, f64, f64) {
+    // Fold z (and dz with it) before squaring
+    let (x, y, dx, dy) = match formula {
+        Formula::Mandelbrot | Formula::Celtic => (x, y, dx, dy),
+        Formula::BurningShip => (x.abs(), y.abs(), dx * x.signum(), dy * y.signum()),
+        Formula::Tricorn => (x, -y, dx, -dy),
+    };
+
+    // z^2 and dz' = 2 * z * dz + 1
+    let mut zx = x * x - y * y;
+    let zy = 2.0 * x * y;
+    let mut new_dx = 2.0 * (x * dx - y * dy) + 1.0;
+    let new_dy = 2.0 * (x * dy + y * dx);
+
+    if formula == Formula::Celtic {
+        // Fold the real part of z^2
+        new_dx = (new_dx - 1.0) * zx.signum() + 1.0;
+        zx = zx.abs();
+    }
+
+    (zx + cx, zy + cy, new_dx, new_dy)
+}
+
+/// Pixel spacing of each progressive refinement pass, coarsest first
+pub const PROGRESSIVE_STEPS: [u32; 4] = [8, 4, 2, 1];
+
+/// Maps frame pixel coordinates to points in the complex plane
+pub struct ViewMapping {
+    x_min: f64,
+    y_min: f64,
+    pub x_scale: f64,
+    pub y_scale: f64,
+}
+
+impl ViewMapping {
+    fn new(req: &RenderStripRequest) -> Self {
+        Self::from_view(req.width, req.total_height, req.center_x, req.center_y, req.zoom)
+    }
+
+    pub fn from_view(width: u32, height: u32, center_x: f64, center_y: f64, zoom: f64) -> Self {
+        // Aspect ratio preserved, width determines scale
+        let aspect = height as f64 / width as f64;
+        let view_width = 4.0 / zoom;
+        let view_height = view_width * aspect;
+
+        Self {
+            x_min: center_x - view_width / 2.0,
+            y_min: center_y - view_height / 2.0,
+            x_scale: view_width / width as f64,
+            y_scale: view_height / height as f64,
+        }
+    }
+
+    #[inline]
+    pub fn point(&self, px: u32, py: u32) -> (f64, f64) {
+        (
+            self.x_min + px as f64 * self.x_scale,
+            self.y_min + py as f64 * self.y_scale,
+        )
+    }
+}
+
+/// Maps frame pixels to points on log-polar rings around the view centre
+///
+/// Columns sweep the angle once around the full circle and each row steps
+/// the log-radius inwards by the same amount as one column of angle, so
+/// pixels stay square. Row 0 is the circle through the corners of a square
+/// view at the requested zoom, and every `width * ln 2 / 2pi` rows further
+/// down is one octave (2x) deeper.
+struct ExponentialMapping {
     center_x: f64,
     center_y: f64,
-    zoom: f64,
-    max_iterations: u32,
-    palette: &[(u8, u8, u8)],
-    colour_interior_enabled: bool,
-) -> Vec<u8> {
-    let height = y_end - y_start;
-    let mut pixels = Vec::with_capacity((width * height * 3) as usize);
-
-    // Calculate the view bounds
-    // Aspect ratio preserved, width determines scale
-    let aspect = total_height as f64 / width as f64;
-    let view_width = 4.0 / zoom;
-    let view_height = view_width * aspect;
-
-    let x_min = center_x - view_width / 2.0;
-    let y_min = center_y - view_height / 2.0;
-
-    let x_scale = view_width / width as f64;
-    let y_scale = view_height / total_height as f64;
-
-    for py in y_start..y_end {
-        for px in 0..width {
-            let cx = x_min + px as f64 * x_scale;
-            let cy = y_min + py as f64 * y_scale;
-
-            let result = mandelbrot_point(cx, cy, max_iterations);
-
-            let (r, g, b) = if result.in_set {
-                if colour_interior_enabled {
-                    colour_interior(result.final_x, result.final_y, palette)
-                } else {
-                    (0, 0, 0)
+    log_radius: f64,
+    step: f64,
+}
+
+impl ExponentialMapping {
+    fn new(req: &RenderStripRequest) -> Self {
+        let half_width = 2.0 / req.zoom;
+        Self {
+            center_x: req.center_x,
+            center_y: req.center_y,
+            log_radius: (half_width * std::f64::consts::SQRT_2).ln(),
+            step: TAU / req.width as f64,
+        }
+    }
+
+    #[inline]
+    fn radius(&self, py: u32) -> f64 {
+        (self.log_radius - py as f64 * self.step).exp()
+    }
+
+    #[inline]
+    fn point(&self, px: u32, py: u32) -> (f64, f64) {
+        let radius = self.radius(py);
+        let (sin, cos) = (px as f64 * self.step).sin_cos();
+        (self.center_x + radius * cos, self.center_y + radius * sin)
+    }
+}
+
+/// Pixel-to-plane mapping for a request's projection
+enum Mapping {
+    Rectilinear(ViewMapping),
+    Exponential(ExponentialMapping),
+}
+
+impl Mapping {
+    fn new(req: &RenderStripRequest) -> Self {
+        match req.projection {
+            Projection::Rectilinear => Mapping::Rectilinear(ViewMapping::new(req)),
+            Projection::Exponential => Mapping::Exponential(ExponentialMapping::new(req)),
+        }
+    }
+
+    #[inline]
+    fn point(&self, px: u32, py: u32) -> (f64, f64) {
+        match self {
+            Mapping::Rectilinear(view) => view.point(px, py),
+            Mapping::Exponential(view) => view.point(px, py),
+        }
+    }
+
+    /// Width of a pixel on row `py` in the complex plane
+    #[inline]
+    fn pixel_size(&self, py: u32) -> f64 {
+        match self {
+            Mapping::Rectilinear(view) => view.x_scale,
+            Mapping::Exponential(view) => view.radius(py) * view.step,
+        }
+    }
+}
+
+/// Iteration value recorded for points inside the set
+pub const INTERIOR: f32 = -1.0;
+
+/// Palette tables a strip colours with
+pub struct Palettes {
+    /// The frame palette
+    pub frame: Arc<Vec<Rgb>>,
+    /// One table per request layer; layers beyond the end use `frame`
+    pub layers: Vec<Arc<Vec<Rgb>>>,
+}
+
+impl From<Vec<Rgb>> for Palettes {
+    fn from(frame: Vec<Rgb>) -> Self {
+        Self { frame: Arc::new(frame), layers: Vec::new() }
+    }
+}
+
+/// A request's colouring layers, or the default stack if it lists none
+fn effective_layers(req: &RenderStripRequest) -> Vec<Layer> {
+    if req.layers.is_empty() {
+        Layer::defaults(req.interior)
+    } else {
+        req.layers.clone()
+    }
+}
+
+/// A strip's colouring layers, each with the palette table it samples
+struct Compositor<'a> {
+    layers: Vec<(Layer, &'a [Rgb])>,
+    /// Colour of `InteriorMode::Solid`
+    interior_colour: Rgb,
+    /// Whether interior points need their cycle period found
+    needs_period: bool,
+}
+
+impl<'a> Compositor<'a> {
+    fn new(req: &RenderStripRequest, palettes: &'a Palettes) -> Self {
+        let layers: Vec<_> = effective_layers(req)
+            .into_iter()
+            .enumerate()
+            .map(|(i, layer)| (layer, palettes.layers.get(i).unwrap_or(&palettes.frame).as_slice()))
+            .collect();
+        let needs_period = layers
+            .iter()
+            .any(|(layer, _)| layer.source == LayerSource::Interior { mode: InteriorMode::Period });
+        Self {
+            layers,
+            interior_colour: req.interior_colour.map(|c| c as f32 / 255.0),
+            needs_period,
+        }
+    }
+
+    /// Composite every layer covering a point, bottom to top over black
+    #[inline]
+    fn colour(&self, result: &MandelbrotResult, mapping: &ColourMapping, pixel_size: f64) -> Rgb {
+        let mut colour = [0.0; 3];
+        for (layer, palette) in &self.layers {
+            let value = match layer.source {
+                LayerSource::Smooth if !result.in_set => smooth_colour(result.smooth_iter, mapping, palette),
+                LayerSource::DistanceEstimate if !result.in_set => [distance_shade(result, pixel_size); 3],
+                LayerSource::OrbitTrap(trap) if result.trap_distance.is_finite() => {
+                    cycle_palette(palette, result.trap_distance * trap.scale)
                 }
-            } else {
-                smooth_colour(result.smooth_iter, palette)
+                LayerSource::Interior { mode } if result.in_set => self.interior(mode, result, palette),
+                _ => continue,
             };
+            colour = layer.blend.apply(colour, value, layer.opacity);
+        }
+        colour
+    }
 
-            pixels.push(r);
-            pixels.push(g);
-            pixels.push(b);
+    /// Colour of a point inside the set
+    #[inline]
+    fn interior(&self, mode: InteriorMode, result: &MandelbrotResult, palette: &[Rgb]) -> Rgb {
+        match mode {
+            InteriorMode::Solid => self.interior_colour,
+            InteriorMode::Angle => colour_interior(result.final_x, result.final_y, palette),
+            InteriorMode::Magnitude => cycle_palette(palette, result.final_x.hypot(result.final_y) / 2.0),
+            // Golden-ratio steps keep neighbouring periods far apart in the palette
+            InteriorMode::Period => cycle_palette(palette, result.period as f64 * 0.618_033_988_75),
+            InteriorMode::Derivative => {
+                cycle_palette(palette, result.deriv_x.hypot(result.deriv_y).ln_1p() * 0.25)
+            }
+            InteriorMode::OrbitAverage => colour_interior(result.mean_x, result.mean_y, palette),
         }
     }
+}
 
-    pixels
+/// Compute the colour and smooth iteration count of a single point
+#[inline]
+fn render_pixel(
+    req: &RenderStripRequest,
+    iteration: &Iteration,
+    compositor: &Compositor,
+    cx: f64,
+    cy: f64,
+    pixel_size: f64,
+) -> (Rgb, f32) {
+    let mut result = iteration.point(cx, cy, req.max_iterations, &req.bailout);
+    if result.in_set && compositor.needs_period {
+        result.period = iteration.period(cx, cy, &result, req.max_iterations);
+    }
+    let mut colour = compositor.colour(&result, &req.colour_mapping, pixel_size);
+
+    if result.in_set {
+        (colour, INTERIOR)
+    } else {
+        if let Some(lighting) = &req.lighting {
+            if let Some((nx, ny)) = surface_normal(&result) {
+                colour = lighting.shade(colour, nx, ny);
+            }
+        }
+        (colour, result.smooth_iter as f32)
+    }
 }
 
-/// Get a smoothly interpolated colour from the palette
-fn smooth_colour(smooth_iter: f64, palette: &[(u8, u8, u8)]) -> (u8, u8, u8) {
-    let palette_len = palette.len();
+/// Brightness from the exterior distance estimate `|z| ln|z| / |dz|`
+///
+/// Zero at the boundary, rising to one a pixel away. Formulas without a
+/// derivative have no estimate and are left at full brightness.
+#[inline]
+fn distance_shade(result: &MandelbrotResult, pixel_size: f64) -> f32 {
+    let z = result.final_x.hypot(result.final_y);
+    let dz = result.deriv_x.hypot(result.deriv_y);
+    let distance = z * z.ln() / dz;
+    if distance.is_nan() {
+        return 1.0;
+    }
+    (distance / pixel_size).clamp(0.0, 1.0).sqrt() as f32
+}
 
-    // Scale and wrap the iteration count to palette indices
-    let scaled = smooth_iter * 0.1; // Adjust this for colour density
-    let idx1 = (scaled.floor() as usize) % palette_len;
-    let idx2 = (idx1 + 1) % palette_len;
-    let frac = scaled.fract();
+/// Unit normal of the escape-time "surface" at an escaped point
+///
+/// This is the direction of z / dz, which points away from the set and
+/// gives the embossed look when lit.
+#[inline]
+fn surface_normal(result: &MandelbrotResult) -> Option<(f64, f64)> {
+    // u = z / dz = z * conj(dz) / |dz|^2; only the direction matters
+    let ux = result.final_x * result.deriv_x + result.final_y * result.deriv_y;
+    let uy = result.final_y * result.deriv_x - result.final_x * result.deriv_y;
+    let len = (ux * ux + uy * uy).sqrt();
+    if len > 0.0 && len.is_finite() {
+        Some((ux / len, uy / len))
+    } else {
+        None
+    }
+}
+
+/// Render a rectangular region of the Mandelbrot set
+///
+/// The region is `x_start..x_end` by `y_start..y_end` within a frame of
+/// `width` x `total_height` pixels, so full-width strips and square tiles
+/// use the same code path.
+///
+/// Returns pixel data in the request's `pixel_format`, row-major
+pub fn render_strip(req: &RenderStripRequest, palettes: &Palettes) -> Vec<u8> {
+    render_strip_iterations(req, palettes, &AtomicBool::new(false)).unwrap().0
+}
+
+/// Render a region like `render_strip`, also returning the smooth
+/// iteration count of each pixel (`INTERIOR` inside the set)
+///
+/// `cancel` is checked between rows; once it is set the render is abandoned
+/// and `None` returned.
+pub fn render_strip_iterations(
+    req: &RenderStripRequest,
+    palettes: &Palettes,
+    cancel: &AtomicBool,
+) -> Option<(Vec<u8>, Vec<f32>)> {
+    let count = ((req.x_end - req.x_start) * (req.y_end - req.y_start)) as usize;
+    let bytes_per_pixel = req.pixel_format.bytes_per_pixel();
+    let mut pixels = vec![0u8; count * bytes_per_pixel];
+    let mut iterations = Vec::with_capacity(count);
+
+    let view = Mapping::new(req);
+    let iteration = Iteration::new(req);
+    let compositor = Compositor::new(req, palettes);
+
+    let mut out = pixels.chunks_exact_mut(bytes_per_pixel);
+    for py in req.y_start..req.y_end {
+        if cancel.load(Ordering::Relaxed) {
+            return None;
+        }
+        let pixel_size = view.pixel_size(py);
+        for px in req.x_start..req.x_end {
+            let (cx, cy) = view.point(px, py);
+            let (colour, smooth_iter) = render_pixel(req, &iteration, &compositor, cx, cy, pixel_size);
+            req.pixel_format.write_dithered(colour, req.dither.threshold(px, py), out.next().unwrap());
+            iterations.push(smooth_iter);
+        }
+    }
+
+    Some((pixels, iterations))
+}
+
+/// Pixels (relative to the tile origin) first computed by progressive pass `pass`
+///
+/// Pass `n` samples a grid of `PROGRESSIVE_STEPS[n]`, less the points earlier
+/// passes already computed, in row-major order.
+pub fn pass_samples(tile_width: u32, tile_height: u32, pass: usize) -> impl Iterator<Item = (u32, u32)> {
+    let step = PROGRESSIVE_STEPS[pass];
+    let coarser = if pass > 0 { PROGRESSIVE_STEPS[pass - 1] } else { 0 };
+    (0..tile_height)
+        .step_by(step as usize)
+        .flat_map(move |ty| (0..tile_width).step_by(step as usize).map(move |tx| (tx, ty)))
+        .filter(move |&(tx, ty)| coarser == 0 || tx % coarser != 0 || ty % coarser != 0)
+}
+
+/// Render the new samples of one progressive refinement pass
+///
+/// Returns pixel data and smooth iteration counts for the pixels of
+/// `pass_samples`, in order, or `None` if `cancel` was set part way through.
+/// `fill_pass` spreads them over a tile.
+pub fn render_strip_pass(
+    req: &RenderStripRequest,
+    palettes: &Palettes,
+    pass: usize,
+    cancel: &AtomicBool,
+) -> Option<(Vec<u8>, Vec<f32>)> {
+    let view = Mapping::new(req);
+    let iteration = Iteration::new(req);
+    let compositor = Compositor::new(req, palettes);
+    let bytes_per_pixel = req.pixel_format.bytes_per_pixel();
+    let mut pixels = Vec::new();
+    let mut iterations = Vec::new();
 
-    let (r1, g1, b1) = palette[idx1];
-    let (r2, g2, b2) = palette[idx2];
+    let mut row = None;
+    let mut pixel_size = 0.0;
+    for (tx, ty) in pass_samples(req.x_end - req.x_start, req.y_end - req.y_start, pass) {
+        let (px, py) = (req.x_start + tx, req.y_start + ty);
+        if row != Some(py) {
+            if cancel.load(Ordering::Relaxed) {
+                return None;
+            }
+            row = Some(py);
+            pixel_size = view.pixel_size(py);
+        }
 
-    // Linear interpolation
-    let r = lerp(r1, r2, frac);
-    let g = lerp(g1, g2, frac);
-    let b = lerp(b1, b2, frac);
+        let (cx, cy) = view.point(px, py);
+        let (colour, smooth_iter) = render_pixel(req, &iteration, &compositor, cx, cy, pixel_size);
+        let start = pixels.len();
+        pixels.resize(start + bytes_per_pixel, 0);
+        req.pixel_format.write_dithered(colour, req.dither.threshold(px, py), &mut pixels[start..]);
+        iterations.push(smooth_iter);
+    }
+    Some((pixels, iterations))
+}
 
-    (r, g, b)
+/// Fill the block each of a pass's samples covers
+///
+/// The tile at `origin` of `size` lies in `buffer`, `stride` pixels wide with
+/// `channels` values per pixel. After every pass the tile holds a complete, if
+/// blocky, image; after the last it matches `render_strip` exactly.
+pub fn fill_pass<T: Copy>(
+    buffer: &mut [T],
+    stride: u32,
+    channels: usize,
+    origin: (u32, u32),
+    size: (u32, u32),
+    pass: usize,
+    samples: &[T],
+) {
+    let step = PROGRESSIVE_STEPS[pass];
+    let (tile_width, tile_height) = size;
+    for ((tx, ty), sample) in pass_samples(tile_width, tile_height, pass).zip(samples.chunks_exact(channels)) {
+        for by in ty..(ty + step).min(tile_height) {
+            for bx in tx..(tx + step).min(tile_width) {
+                let index = ((origin.1 + by) * stride + origin.0 + bx) as usize * channels;
+                if let Some(dst) = buffer.get_mut(index..index + channels) {
+                    dst.copy_from_slice(sample);
+                }
+            }
+        }
+    }
 }
 
+/// Render the pixels of a reprojected tile that the previous frame can't supply
+///
+/// Points are taken from the request's explicit column and row coordinates.
+/// Skipped pixels are left black with an iteration value of zero; the
+/// coordinator fills them from its copy of the previous frame.
+///
+/// Returns pixel data and the smooth iteration count of each pixel, or
+/// `None` if `cancel` was set part way through
+pub fn render_strip_reprojected(
+    req: &RenderStripRequest,
+    reprojection: &Reprojection,
+    palettes: &Palettes,
+    cancel: &AtomicBool,
+) -> Option<(Vec<u8>, Vec<f32>)> {
+    let skipped = reprojection.skipped();
+    let count = reprojection.xs.len() * reprojection.ys.len();
+    let bytes_per_pixel = req.pixel_format.bytes_per_pixel();
+    let mut pixels = vec![0u8; count * bytes_per_pixel];
+    let mut iterations = vec![0.0f32; count];
+    let iteration = Iteration::new(req);
+    let compositor = Compositor::new(req, palettes);
+    // Reprojection is rectilinear only, so every pixel is the same size
+    let pixel_size = ViewMapping::new(req).x_scale;
+
+    let mut i = 0;
+    for (py, &cy) in (req.y_start..).zip(&reprojection.ys) {
+        if cancel.load(Ordering::Relaxed) {
+            return None;
+        }
+        for (px, &cx) in (req.x_start..).zip(&reprojection.xs) {
+            if !skipped[i] {
+                let (colour, smooth_iter) = render_pixel(req, &iteration, &compositor, cx, cy, pixel_size);
+                let out = &mut pixels[i * bytes_per_pixel..(i + 1) * bytes_per_pixel];
+                req.pixel_format.write_dithered(colour, req.dither.threshold(px, py), out);
+                iterations[i] = smooth_iter;
+            }
+            i += 1;
+        }
+    }
+
+    Some((pixels, iterations))
+}
+
+/// Get a smoothly interpolated colour from the palette
+fn smooth_colour(smooth_iter: f64, mapping: &ColourMapping, palette: &[Rgb]) -> Rgb {
+    // Map the iteration count to a wrapped palette index
+    sample_palette(palette, mapping.position(smooth_iter, palette.len()))
+}
+
+/// Palette colour `cycles` of the way round the palette, wrapping
+#[inline]
+fn cycle_palette(palette: &[Rgb], cycles: f64) -> Rgb {
+    let cycles = if cycles.is_finite() { cycles.rem_euclid(1.0) } else { 0.0 };
+    sample_palette(palette, cycles * palette.len() as f64)
+}
+
+/// Linearly interpolate the palette at a fractional index, wrapping round
 #[inline]
-fn lerp(a: u8, b: u8, t: f64) -> u8 {
-    let result = a as f64 * (1.0 - t) + b as f64 * t;
-    result.clamp(0.0, 255.0) as u8
+fn sample_palette(palette: &[Rgb], position: f64) -> Rgb {
+    let palette_len = palette.len();
+    let idx1 = (position.floor() as usize) % palette_len;
+    let idx2 = (idx1 + 1) % palette_len;
+    let frac = position.fract() as f32;
+
+    let (from, to) = (palette[idx1], palette[idx2]);
+    [0, 1, 2].map(|c| from[c] + (to[c] - from[c]) * frac)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::colour::{BlendMode, Dither, Palette, PixelFormat, Preset, TrapShape};
+    use crate::messages::{FormulaSequence, FormulaStep};
 
     #[test]
     fn test_mandelbrot_in_set() {
         // Origin is in the Mandelbrot set
-        let result = mandelbrot_point(0.0, 0.0, 100);
+        let result = mandelbrot_point(0.0, 0.0, 100, &Bailout::default());
         assert!(result.in_set);
         assert_eq!(result.smooth_iter, 100.0);
     }
@@ -158,8 +858,335 @@ mod tests {
     #[test]
     fn test_mandelbrot_escapes() {
         // Point well outside the set
-        let result = mandelbrot_point(2.0, 2.0, 100);
+        let result = mandelbrot_point(2.0, 2.0, 100, &Bailout::default());
         assert!(!result.in_set);
         assert!(result.smooth_iter < 10.0);
+
+        // The default bailout keeps the classic n + 1 - log2(log2 |z|)
+        let (cx, cy) = (0.4, 0.6);
+        let result = mandelbrot_point(cx, cy, 100, &Bailout::default());
+        assert!(!result.in_set);
+        let (mut x, mut y, mut n) = (0.0f64, 0.0f64, 0);
+        while x * x + y * y <= 256.0 * 256.0 {
+            (x, y) = (x * x - y * y + cx, 2.0 * x * y + cy);
+            n += 1;
+        }
+        let classic = n as f64 + 1.0 - ((x * x + y * y).ln() / 2.0 / std::f64::consts::LN_2).log2();
+        assert!((result.smooth_iter - classic).abs() < 1e-9);
+    }
+
+    fn request(x_start: u32, x_end: u32, y_start: u32, y_end: u32) -> RenderStripRequest {
+        RenderStripRequest {
+            frame_id: 0,
+            width: 64,
+            x_start,
+            x_end,
+            y_start,
+            y_end,
+            total_height: 48,
+            center_x: -0.5,
+            center_y: 0.0,
+            zoom: 1.0,
+            max_iterations: 64,
+            palette_hash: 0,
+            interior: InteriorMode::Solid,
+            interior_colour: [0; 3],
+            progressive: false,
+            reprojection: None,
+            lighting: None,
+            projection: Projection::Rectilinear,
+            formula: FormulaSequence::default(),
+            program: None,
+            bailout: Bailout::default(),
+            return_iterations: false,
+            colour_mapping: ColourMapping::default(),
+            pixel_format: PixelFormat::Rgb8,
+            layers: Vec::new(),
+            dither: Dither::None,
+        }
+    }
+
+    #[test]
+    fn test_tile_matches_full_frame() {
+        let palette = Palettes::from(Palette::default().generate(256));
+        // Dither patterns follow frame coordinates, so tiles still line up
+        for dither in [Dither::None, Dither::Bayer, Dither::BlueNoise] {
+            let dithered = |x_start, x_end, y_start, y_end| RenderStripRequest {
+                dither,
+                ..request(x_start, x_end, y_start, y_end)
+            };
+            let full = render_strip(&dithered(0, 64, 0, 48), &palette);
+            let tile = render_strip(&dithered(16, 40, 8, 20), &palette);
+            assert_eq!(tile.len(), 24 * 12 * 3);
+
+            for row in 0..12 {
+                let full_offset = ((8 + row) * 64 + 16) * 3;
+                let tile_offset = row * 24 * 3;
+                assert_eq!(
+                    &tile[tile_offset..tile_offset + 24 * 3],
+                    &full[full_offset..full_offset + 24 * 3]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pixel_formats_agree() {
+        let palette = Palettes::from(Palette::default().generate(256));
+        let mut req = request(0, 32, 0, 24);
+        let narrow = render_strip(&req, &palette);
+        req.pixel_format = PixelFormat::Rgb16;
+        let wide = render_strip(&req, &palette);
+        req.pixel_format = PixelFormat::RgbF32;
+        let linear = render_strip(&req, &palette);
+        assert_eq!(wide.len(), narrow.len() * 2);
+        assert_eq!(linear.len(), narrow.len() * 4);
+
+        for (i, &byte) in narrow.iter().enumerate() {
+            let deep = u16::from_le_bytes([wide[i * 2], wide[i * 2 + 1]]);
+            assert_eq!((deep as f64 / 257.0).round() as u8, byte);
+
+            let light = f32::from_le_bytes(linear[i * 4..i * 4 + 4].try_into().unwrap());
+            assert!((0.0..=1.0).contains(&light));
+            // Linear light is never brighter than the encoded value
+            assert!(light <= deep as f32 / 65535.0 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_progressive_passes_converge() {
+        let palette = Palettes::from(Palette::default().generate(256));
+        let req = request(8, 53, 4, 31);
+        let running = AtomicBool::new(false);
+        let (full, full_iterations) = render_strip_iterations(&req, &palette, &running).unwrap();
+
+        let mut pixels = vec![0u8; full.len()];
+        let mut iterations = vec![0.0f32; full_iterations.len()];
+        let size = (req.x_end - req.x_start, req.y_end - req.y_start);
+        let mut sampled = 0;
+        for pass in 0..PROGRESSIVE_STEPS.len() {
+            let (data, values) = render_strip_pass(&req, &palette, pass, &running).unwrap();
+            fill_pass(&mut pixels, size.0, 3, (0, 0), size, pass, &data);
+            fill_pass(&mut iterations, size.0, 1, (0, 0), size, pass, &values);
+            sampled += values.len();
+        }
+        assert_eq!(pixels, full);
+        assert_eq!(iterations, full_iterations);
+        // Each pixel is computed and sent once
+        assert_eq!(sampled, full_iterations.len());
+
+        // A cancelled render stops rather than returning a partial tile
+        let cancelled = AtomicBool::new(true);
+        assert!(render_strip_iterations(&req, &palette, &cancelled).is_none());
+        assert!(render_strip_pass(&req, &palette, 0, &cancelled).is_none());
+    }
+
+    #[test]
+    fn test_reprojected_tile_skips_known_pixels() {
+        let palette = Palettes::from(Palette::default().generate(256));
+        let req = request(0, 64, 0, 48);
+        let running = AtomicBool::new(false);
+        let (full, full_iterations) = render_strip_iterations(&req, &palette, &running).unwrap();
+
+        // Sample on the same grid as the full render, skipping alternate pixels
+        let view = ViewMapping::new(&req);
+        let xs: Vec<f64> = (0..64).map(|px| view.point(px, 0).0).collect();
+        let ys: Vec<f64> = (0..48).map(|py| view.point(0, py).1).collect();
+        let skip: Vec<bool> = (0..64 * 48).map(|i| i % 2 == 0).collect();
+        let reprojection = Reprojection::new(xs, ys, &skip);
+        assert_eq!(reprojection.skipped(), skip);
+
+        let (pixels, iterations) = render_strip_reprojected(&req, &reprojection, &palette, &running).unwrap();
+        for i in 0..64 * 48 {
+            if skip[i] {
+                assert_eq!(&pixels[i * 3..i * 3 + 3], &[0, 0, 0]);
+                assert_eq!(iterations[i], 0.0);
+            } else {
+                assert_eq!(&pixels[i * 3..i * 3 + 3], &full[i * 3..i * 3 + 3]);
+                assert_eq!(iterations[i], full_iterations[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_surface_normal_points_outwards() {
+        // On the real axis right of the set, the normal points along +x
+        let result = mandelbrot_point_derivative(1.0, 0.0, 100, &Bailout::default());
+        let (nx, ny) = surface_normal(&result).unwrap();
+        assert!((nx - 1.0).abs() < 1e-9);
+        assert!(ny.abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_exponential_mapping_octaves() {
+        let mut req = request(0, 64, 0, 48);
+        req.zoom = 2.0;
+        let view = ExponentialMapping::new(&req);
+
+        // Every row is the same ratio deeper; a quarter turn is a quarter of the width
+        let radius = |py| {
+            let (x, y) = view.point(0, py);
+            ((x - req.center_x).powi(2) + (y - req.center_y).powi(2)).sqrt()
+        };
+        assert!((radius(0) - 2.0_f64.sqrt()).abs() < 1e-12);
+        assert!((radius(10) / radius(11) - (TAU / 64.0).exp()).abs() < 1e-12);
+
+        let (x, y) = view.point(16, 0);
+        assert!((x - req.center_x).abs() < 1e-12);
+        assert!(y > req.center_y);
+    }
+
+    #[test]
+    fn test_hybrid_mandelbrot_matches_plain() {
+        for &(cx, cy) in &[(0.0, 0.0), (-0.75, 0.1), (0.3, 0.5), (2.0, 2.0)] {
+            let plain = mandelbrot_point(cx, cy, 200, &Bailout::default());
+            let hybrid = hybrid_point(cx, cy, 200, &Bailout::default(), &[Formula::Mandelbrot], None);
+            assert_eq!(plain.in_set, hybrid.in_set);
+            assert!((plain.smooth_iter - hybrid.smooth_iter).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_formula_schedule() {
+        let sequence = FormulaSequence(vec![
+            FormulaStep { formula: Formula::Mandelbrot, iterations: 2 },
+            FormulaStep { formula: Formula::BurningShip, iterations: 1 },
+        ]);
+        assert_eq!(
+            sequence.schedule().unwrap(),
+            vec![Formula::Mandelbrot, Formula::Mandelbrot, Formula::BurningShip]
+        );
+        assert!(FormulaSequence::default().schedule().is_none());
+
+        // Burning Ship differs from Mandelbrot off the real axis
+        let ship = hybrid_point(-1.7, -0.05, 100, &Bailout::default(), &[Formula::BurningShip], None);
+        let mandel = mandelbrot_point(-1.7, -0.05, 100, &Bailout::default());
+        assert_ne!(ship.smooth_iter, mandel.smooth_iter);
+    }
+
+    #[test]
+    fn test_custom_formula_matches_mandelbrot() {
+        // Starting at z = c is one iteration ahead of starting at zero
+        let program = Program::compile("z^2 + c").unwrap();
+        for &(cx, cy) in &[(0.0, 0.0), (-0.75, 0.1), (0.3, 0.5), (2.0, 2.0)] {
+            let plain = mandelbrot_point(cx, cy, 200, &Bailout::default());
+            let custom = custom_point(cx, cy, 199, &Bailout::default(), &program, None);
+            assert_eq!(plain.in_set, custom.in_set);
+            if !plain.in_set {
+                assert!((plain.smooth_iter - (custom.smooth_iter + 1.0)).abs() < 0.1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bailout_norms_smooth_across_bands() {
+        // Along a line of c values the smooth count should have no big jumps
+        // where the integer escape iteration changes, for every norm
+        for norm in [
+            BailoutNorm::Euclidean,
+            BailoutNorm::Manhattan,
+            BailoutNorm::Chebyshev,
+        ] {
+            let bailout = Bailout { radius: 1000.0, norm };
+            let mut previous: Option<f64> = None;
+            for i in 0..400 {
+                let cx = 0.3 + i as f64 * 0.0005;
+                let result = mandelbrot_point(cx, 0.01, 500, &bailout);
+                assert!(!result.in_set);
+                if let Some(previous) = previous {
+                    assert!((result.smooth_iter - previous).abs() < 0.5, "{:?} at {}", norm, cx);
+                }
+                previous = Some(result.smooth_iter);
+            }
+        }
+
+        // Partial norms still produce values within the escape band
+        let bailout = Bailout { radius: 4.0, norm: BailoutNorm::Imaginary };
+        let result = mandelbrot_point(0.1, 1.2, 100, &bailout);
+        assert!(!result.in_set);
+        assert!(result.smooth_iter.is_finite());
+    }
+
+    #[test]
+    fn test_colouring_layers() {
+        let palette = Palettes::from(Palette::default().generate(256));
+
+        // The implicit stack matches the old smooth / interior choice
+        let mut req = request(0, 64, 0, 48);
+        req.interior = InteriorMode::Angle;
+        let implicit = render_strip(&req, &palette);
+        req.layers = vec![
+            Layer::new(LayerSource::Smooth),
+            Layer::new(LayerSource::Interior { mode: InteriorMode::Angle }),
+        ];
+        assert_eq!(render_strip(&req, &palette), implicit);
+
+        // Multiplying by distance shading only ever darkens
+        let mut shaded = Layer::new(LayerSource::DistanceEstimate);
+        shaded.blend = BlendMode::Multiply;
+        req.layers.push(shaded);
+        let darkened = render_strip(&req, &palette);
+        assert!(darkened.iter().zip(&implicit).all(|(d, i)| d <= i));
+        assert!(darkened.iter().zip(&implicit).any(|(d, i)| d < i));
+
+        // An orbit trap reaches inside the set, with its own palette
+        let trap = OrbitTrap { shape: TrapShape::Cross, x: 0.0, y: 0.0, radius: 1.0, scale: 4.0 };
+        let mut req = request(0, 64, 0, 48);
+        req.layers = vec![Layer::new(LayerSource::OrbitTrap(trap))];
+        let palettes = Palettes {
+            frame: Arc::clone(&palette.frame),
+            layers: vec![Arc::new(Palette::Preset(Preset::Ocean).generate(256))],
+        };
+        let (pixels, iterations) = render_strip_iterations(&req, &palettes, &AtomicBool::new(false)).unwrap();
+        assert!(pixels
+            .chunks(3)
+            .zip(&iterations)
+            .any(|(pixel, &iteration)| iteration == INTERIOR && pixel != [0, 0, 0]));
+
+        // Tracking the trap doesn't change the iteration itself
+        let plain = mandelbrot_point(-0.75, 0.1, 200, &Bailout::default());
+        let trapped = hybrid_point(-0.75, 0.1, 200, &Bailout::default(), &[Formula::Mandelbrot], Some(&trap));
+        assert_eq!(plain.smooth_iter, trapped.smooth_iter);
+        assert!(trapped.trap_distance.is_finite());
+    }
+
+    #[test]
+    fn test_interior_modes() {
+        // Main cardioid, period-2 bulb and the period-3 "rabbit" bulb
+        let mut req = request(0, 1, 0, 1);
+        req.interior = InteriorMode::Period;
+        for iteration in [Iteration::Mandelbrot, Iteration::Hybrid(vec![Formula::Mandelbrot], None)] {
+            for (cx, cy, period) in [(-0.1, 0.1, 1), (-1.0, 0.05, 2), (-0.12, 0.75, 3)] {
+                let result = iteration.point(cx, cy, 1000, &req.bailout);
+                assert!(result.in_set);
+                assert_eq!(iteration.period(cx, cy, &result, 1000), period, "{} {}", cx, cy);
+            }
+        }
+
+        // Orbit averages switch to the general loop, which records the mean;
+        // a fixed-point orbit averages out near its fixed point
+        req.interior = InteriorMode::OrbitAverage;
+        let iteration = Iteration::new(&req);
+        assert!(matches!(iteration, Iteration::Hybrid(_, None)));
+        let result = iteration.point(-0.1, 0.0, 1000, &req.bailout);
+        let fixed = (1.0 - (1.0_f64 + 0.4).sqrt()) / 2.0;
+        assert!((result.mean_x - fixed).abs() < 0.01);
+
+        // Every mode fills the interior differently from solid black
+        let palette = Palettes::from(Palette::default().generate(256));
+        let mut req = request(0, 64, 0, 48);
+        req.interior_colour = [10, 20, 30];
+        let solid = render_strip(&req, &palette);
+        assert!(solid.chunks(3).any(|pixel| pixel == [10, 20, 30]));
+        for mode in [
+            InteriorMode::Angle,
+            InteriorMode::Magnitude,
+            InteriorMode::Period,
+            InteriorMode::Derivative,
+            InteriorMode::OrbitAverage,
+        ] {
+            req.interior = mode;
+            assert_ne!(render_strip(&req, &palette), solid, "{:?}", mode);
+        }
     }
 }
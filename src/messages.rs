@@ -1,8 +1,10 @@
-/// Shared message types for coordinator-worker and client-coordinator communication
+//! Shared message types for coordinator-worker and client-coordinator communication
 
+use base64::Engine;
 use serde::{Deserialize, Serialize};
 
-use crate::colour::Palette;
+use crate::colour::{ColourMapping, Colouring, Dither, InteriorMode, Layer, Lighting, Palette, PixelFormat};
+use crate::formula::Program;
 
 // ============================================================================
 // Worker <-> Coordinator messages
@@ -21,16 +23,39 @@ pub enum WorkerToCoordinator {
     ProfileResult { worker_id: String, compute_ms: u64 },
     /// Rendered strip result
     StripResult(StripResult),
+    /// A strip was given up without finishing because its frame was
+    /// cancelled; the worker has room for another
+    StripAbandoned { worker_id: String, frame_id: u64, x_start: u32, y_start: u32 },
+    /// Rendering a strip went wrong, e.g. panicked; it may be retried elsewhere
+    StripFailed { worker_id: String, frame_id: u64, x_start: u32, y_start: u32, message: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StripResult {
     pub worker_id: String,
     pub frame_id: u64,
+    pub x_start: u32,
+    pub x_end: u32,
     pub y_start: u32,
     pub y_end: u32,
+    /// Refinement pass this data belongs to (always 0 when not progressive)
+    ///
+    /// A progressive pass carries only the pixels it computed, in the order
+    /// of `mandelbrot::pass_samples`, rather than the whole tile.
+    #[serde(default)]
+    pub pass: u32,
+    /// Whether this is the tile's final, full-resolution data
+    #[serde(default = "default_true")]
+    pub last_pass: bool,
     pub compute_ms: u64,
     pub data: String, // Base64 encoded RGB
+    /// Smooth iteration count per pixel, for reprojected requests
+    #[serde(default)]
+    pub iterations: Option<String>, // Base64 encoded little-endian f32
+}
+
+fn default_true() -> bool {
+    true
 }
 
 /// Messages from coordinator to worker
@@ -42,14 +67,28 @@ pub enum CoordinatorToWorker {
     Registered { worker_id: String },
     /// Request to run profiling
     RunProfile { width: u32, height: u32 },
-    /// Request to render a strip
-    RenderStrip(RenderStripRequest),
+    /// Request to render a strip, with the definitions of any palettes it
+    /// uses that the worker doesn't hold
+    RenderStrip {
+        strip: Box<RenderStripRequest>,
+        #[serde(default)]
+        palettes: Vec<(u64, Palette)>,
+    },
+    /// Abandon the frame's tiles, queued or in progress; each tile given up is
+    /// answered with `StripAbandoned` instead of a result
+    CancelFrame { frame_id: u64 },
 }
 
+/// Request to render a rectangular region of a frame
+///
+/// `width` and `total_height` describe the whole frame; the region to render
+/// is `x_start..x_end` by `y_start..y_end` within it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RenderStripRequest {
     pub frame_id: u64,
     pub width: u32,
+    pub x_start: u32,
+    pub x_end: u32,
     pub y_start: u32,
     pub y_end: u32,
     pub total_height: u32,
@@ -57,10 +96,257 @@ pub struct RenderStripRequest {
     pub center_y: f64,
     pub zoom: f64,
     pub max_iterations: u32,
+    /// `hash` of the palette, defined on the worker by this or an earlier strip
+    pub palette_hash: u64,
     #[serde(default)]
-    pub palette: Palette,
+    pub interior: InteriorMode,
+    /// Colour of `InteriorMode::Solid`
+    #[serde(default)]
+    pub interior_colour: [u8; 3],
+    /// Render coarse-to-fine and report each refinement pass
+    #[serde(default)]
+    pub progressive: bool,
+    /// Sample positions and reused pixels when reprojecting the last frame
+    #[serde(default)]
+    pub reprojection: Option<Reprojection>,
+    /// Shade the exterior as a lit 3D surface
+    #[serde(default)]
+    pub lighting: Option<Lighting>,
+    #[serde(default)]
+    pub projection: Projection,
+    #[serde(default)]
+    pub formula: FormulaSequence,
+    /// Compiled user-defined formula; overrides `formula` when present
+    #[serde(default)]
+    pub program: Option<Program>,
+    #[serde(default)]
+    pub bailout: Bailout,
+    /// Also send back each pixel's smooth iteration count
+    #[serde(default)]
+    pub return_iterations: bool,
+    #[serde(default)]
+    pub colour_mapping: ColourMapping,
+    #[serde(default)]
+    pub pixel_format: PixelFormat,
+    /// Colouring layers, palettes given by hash; empty for the default stack
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+    #[serde(default)]
+    pub dither: Dither,
+}
+
+impl RenderStripRequest {
+    /// Palettes the strip uses: the frame palette, then each layer's own, in
+    /// the order workers look them up
+    pub fn palette_hashes(&self) -> impl Iterator<Item = u64> + '_ {
+        std::iter::once(self.palette_hash).chain(self.layers.iter().filter_map(|layer| layer.palette_hash))
+    }
+}
+
+/// Norm used to decide when an orbit has escaped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BailoutNorm {
+    /// |z|
+    #[default]
+    Euclidean,
+    /// |Re z| + |Im z|
+    Manhattan,
+    /// max(|Re z|, |Im z|)
+    Chebyshev,
+    /// |Re z|
+    Real,
+    /// |Im z|
+    Imaginary,
+}
+
+/// Escape test: an orbit escapes once its norm exceeds `radius`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Bailout {
+    #[serde(default = "default_bailout_radius")]
+    pub radius: f64,
     #[serde(default)]
-    pub colour_interior: bool,
+    pub norm: BailoutNorm,
+}
+
+fn default_bailout_radius() -> f64 {
+    256.0
+}
+
+impl Default for Bailout {
+    fn default() -> Self {
+        Self {
+            radius: default_bailout_radius(),
+            norm: BailoutNorm::default(),
+        }
+    }
+}
+
+impl Bailout {
+    /// Size of z = (x, y) under the chosen norm
+    #[inline]
+    pub fn measure(&self, x: f64, y: f64) -> f64 {
+        match self.norm {
+            BailoutNorm::Euclidean => (x * x + y * y).sqrt(),
+            BailoutNorm::Manhattan => x.abs() + y.abs(),
+            BailoutNorm::Chebyshev => x.abs().max(y.abs()),
+            BailoutNorm::Real => x.abs(),
+            BailoutNorm::Imaginary => y.abs(),
+        }
+    }
+
+    /// Whether z = (x, y) is still inside the bailout region
+    #[inline]
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        match self.norm {
+            // Avoid the square root on the common path
+            BailoutNorm::Euclidean => x * x + y * y <= self.radius * self.radius,
+            _ => self.measure(x, y) <= self.radius,
+        }
+    }
+
+    /// Check the radius is usable; smooth colouring needs it above 1
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.radius.is_finite() && self.radius > 1.0) {
+            return Err(format!("Bailout radius must be greater than 1, got {}", self.radius));
+        }
+        Ok(())
+    }
+}
+
+/// Escape-time iteration formulas, all of the form z -> f(z)^2 + c
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Formula {
+    #[default]
+    Mandelbrot,
+    /// z -> (|Re z| + i|Im z|)^2 + c
+    BurningShip,
+    /// z -> conj(z)^2 + c
+    Tricorn,
+    /// z -> |Re(z^2)| + i Im(z^2) + c
+    Celtic,
+}
+
+/// One entry of a hybrid formula: `iterations` steps of `formula`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormulaStep {
+    pub formula: Formula,
+    #[serde(default = "default_step_iterations")]
+    pub iterations: u32,
+}
+
+fn default_step_iterations() -> u32 {
+    1
+}
+
+/// Hybrid formula, applied in order and repeated until the orbit escapes
+///
+/// Serialises as a plain list, e.g.
+/// `[{"formula": "mandelbrot", "iterations": 2}, {"formula": "burning_ship"}]`.
+/// An empty sequence is the standard Mandelbrot set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(transparent)]
+pub struct FormulaSequence(pub Vec<FormulaStep>);
+
+impl FormulaSequence {
+    /// Longest cycle, in iterations, a sequence may describe
+    pub const MAX_CYCLE: u32 = 1024;
+
+    /// Expand into the formula for each iteration of one cycle
+    ///
+    /// Returns `None` for plain Mandelbrot, which has a faster dedicated loop.
+    pub fn schedule(&self) -> Option<Vec<Formula>> {
+        let schedule: Vec<Formula> = self
+            .0
+            .iter()
+            .flat_map(|step| std::iter::repeat_n(step.formula, step.iterations as usize))
+            .collect();
+        if schedule.iter().all(|f| *f == Formula::Mandelbrot) {
+            None
+        } else {
+            Some(schedule)
+        }
+    }
+
+    /// Check the sequence is small enough to expand
+    pub fn validate(&self) -> Result<(), String> {
+        let cycle: u64 = self.0.iter().map(|step| step.iterations as u64).sum();
+        if cycle > Self::MAX_CYCLE as u64 {
+            return Err(format!(
+                "Formula sequence cycle of {} iterations exceeds {}",
+                cycle,
+                Self::MAX_CYCLE
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How frame pixels map onto the complex plane
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Projection {
+    /// Ordinary flat view of `4 / zoom` units across
+    #[default]
+    Rectilinear,
+    /// Log-polar strip around the centre: x is angle, y is log-radius,
+    /// covering many zoom octaves in one image for unwrapping into video
+    Exponential,
+}
+
+/// Explicit sampling grid for a tile of a reprojected frame
+///
+/// Reused rows and columns keep the exact coordinate they were computed at in
+/// an earlier frame, so positions are given per column and per row rather
+/// than derived from the view.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reprojection {
+    /// Real coordinate of each tile column
+    pub xs: Vec<f64>,
+    /// Imaginary coordinate of each tile row
+    pub ys: Vec<f64>,
+    /// Pixels already known from the previous frame, one bit per pixel
+    pub skip: String, // Base64 encoded bitmask, row-major, LSB first
+}
+
+impl Reprojection {
+    pub fn new(xs: Vec<f64>, ys: Vec<f64>, skip: &[bool]) -> Self {
+        let mut bits = vec![0u8; skip.len().div_ceil(8)];
+        for (i, _) in skip.iter().enumerate().filter(|(_, s)| **s) {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+        Self {
+            xs,
+            ys,
+            skip: base64::engine::general_purpose::STANDARD.encode(&bits),
+        }
+    }
+
+    /// Decode the skip mask into one flag per tile pixel
+    pub fn skipped(&self) -> Vec<bool> {
+        let bits = base64::engine::general_purpose::STANDARD
+            .decode(&self.skip)
+            .unwrap_or_default();
+        (0..self.xs.len() * self.ys.len())
+            .map(|i| bits.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) != 0))
+            .collect()
+    }
+}
+
+/// Encode per-pixel iteration data for transport
+pub fn encode_iterations(values: &[f32]) -> String {
+    let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    base64::engine::general_purpose::STANDARD.encode(&bytes)
+}
+
+/// Decode per-pixel iteration data sent with `encode_iterations`
+pub fn decode_iterations(data: &str) -> Result<Vec<f32>, base64::DecodeError> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(data)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect())
 }
 
 // ============================================================================
@@ -73,7 +359,13 @@ pub struct RenderStripRequest {
 #[serde(rename_all = "snake_case")]
 pub enum ClientToCoordinator {
     /// Request a frame
-    RequestFrame(FrameRequest),
+    RequestFrame(Box<FrameRequest>),
+    /// Stop rendering a frame the client no longer wants, or all of its
+    /// frames when no id is given, whether queued or in flight
+    CancelFrame {
+        #[serde(default)]
+        frame_id: Option<u64>,
+    },
     /// Request current status
     GetStatus,
 }
@@ -88,8 +380,78 @@ pub struct FrameRequest {
     pub max_iterations: u32,
     #[serde(default)]
     pub palette: Palette,
+    /// Colouring inside the set; the older `colour_interior: true/false`
+    /// is still accepted
+    #[serde(default, alias = "colour_interior", deserialize_with = "InteriorMode::deserialize_compat")]
+    pub interior: InteriorMode,
+    /// Colour of `InteriorMode::Solid`
+    #[serde(default)]
+    pub interior_colour: [u8; 3],
+    /// Stream coarse previews before the full-resolution frame
+    #[serde(default)]
+    pub progressive: bool,
+    /// Reuse pixels from this client's previous frame where the views overlap
+    #[serde(default)]
+    pub reproject: bool,
+    /// Shade the exterior as a lit 3D surface
+    #[serde(default)]
+    pub lighting: Option<Lighting>,
+    #[serde(default)]
+    pub projection: Projection,
+    /// Hybrid formula schedule; empty for the standard Mandelbrot set
     #[serde(default)]
-    pub colour_interior: bool,
+    pub formula: FormulaSequence,
+    /// User-defined formula such as `z = z^3 + c*sin(z)`; overrides `formula`
+    #[serde(default)]
+    pub custom_formula: Option<String>,
+    /// Escape radius and norm; defaults to |z| > 256
+    #[serde(default)]
+    pub bailout: Bailout,
+    /// Histogram colouring ignores `lighting` for escaped points
+    #[serde(default)]
+    pub colouring: Colouring,
+    /// Transfer function, density and offset for smooth colouring
+    #[serde(default)]
+    pub colour_mapping: ColourMapping,
+    /// Pixel format of strips and the returned frame
+    #[serde(default)]
+    pub pixel_format: PixelFormat,
+    /// Colouring layers composited bottom to top over black; when empty,
+    /// smooth colouring outside the set and `interior` inside it
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+    /// Dithering applied when quantising to `pixel_format`
+    #[serde(default)]
+    pub dither: Dither,
+    /// How large a share of the workers the frame gets against other clients' frames
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// Scheduling hint for a frame, relative to other clients' frames
+///
+/// A client's own frames are rendered one at a time in the order requested,
+/// so priority only matters between clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Background work such as pre-rendering or export
+    Low,
+    #[default]
+    Normal,
+    /// Frames a user is waiting to see
+    High,
+}
+
+impl Priority {
+    /// Relative share of the workers while competing with other clients
+    pub fn weight(self) -> f64 {
+        match self {
+            Priority::Low => 0.25,
+            Priority::Normal => 1.0,
+            Priority::High => 4.0,
+        }
+    }
 }
 
 /// Messages from coordinator to client
@@ -99,10 +461,19 @@ pub struct FrameRequest {
 pub enum CoordinatorToClient {
     /// Complete rendered frame
     Frame(FrameResponse),
+    /// Coarse preview of a frame still being refined
+    FramePass(FramePassResponse),
+    /// A frame request was received and will be answered under `frame_id`,
+    /// which `CancelFrame` can refer to from now on
+    FrameAccepted { frame_id: u64 },
+    /// A requested frame was cancelled before it finished
+    FrameCancelled { frame_id: u64 },
     /// Status update
     Status(StatusResponse),
     /// Error
     Error { message: String },
+    /// Custom formula failed to parse; `start..end` is the character range at fault
+    FormulaError { message: String, start: usize, end: usize },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -111,7 +482,18 @@ pub struct FrameResponse {
     pub width: u32,
     pub height: u32,
     pub render_ms: u64,
-    pub data: String, // Base64 encoded RGB
+    pub format: PixelFormat,
+    pub data: String, // Base64 encoded pixels in `format`
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FramePassResponse {
+    pub frame_id: u64,
+    pub pass: u32,
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub data: String, // Base64 encoded pixels in `format`
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
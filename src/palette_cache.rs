@@ -0,0 +1,69 @@
+//! Palette tables held by a worker, mirrored by the coordinator
+//!
+//! Workers keep the palettes they have been sent so each strip only refers to
+//! them by hash. Both ends hold a `PaletteCache` of the same size and apply
+//! the same lookups in the same order - the order strips are sent in - so
+//! they evict the same palettes and the coordinator always knows which ones
+//! a strip has to bring with it.
+
+use std::collections::VecDeque;
+
+/// Palettes kept per worker
+pub const PALETTE_CACHE_SIZE: usize = 32;
+
+/// Least recently used cache of values by palette hash
+pub struct PaletteCache<T> {
+    entries: VecDeque<(u64, T)>,  // Most recently used last
+}
+
+impl<T> PaletteCache<T> {
+    pub fn new() -> Self {
+        Self { entries: VecDeque::new() }
+    }
+
+    /// Look up a palette, marking it as most recently used
+    pub fn get(&mut self, hash: u64) -> Option<&T> {
+        let index = self.entries.iter().position(|(known, _)| *known == hash)?;
+        let entry = self.entries.remove(index)?;
+        self.entries.push_back(entry);
+        self.entries.back().map(|(_, value)| value)
+    }
+
+    /// Add a palette, evicting the least recently used beyond the cache size
+    pub fn insert(&mut self, hash: u64, value: T) {
+        self.entries.retain(|(known, _)| *known != hash);
+        self.entries.push_back((hash, value));
+        if self.entries.len() > PALETTE_CACHE_SIZE {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl<T> Default for PaletteCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut cache = PaletteCache::new();
+        for hash in 0..PALETTE_CACHE_SIZE as u64 {
+            cache.insert(hash, ());
+        }
+        // Using the oldest palette keeps it; the next oldest goes instead
+        assert!(cache.get(0).is_some());
+        cache.insert(100, ());
+        assert!(cache.get(0).is_some());
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(100).is_some());
+    }
+}
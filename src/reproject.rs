@@ -0,0 +1,280 @@
+//! Zoom reprojection - reuse pixels from a client's previous frame
+//!
+//! Consecutive auto-zoom frames differ by a few percent, so most of a new
+//! frame's columns and rows land within half a pixel of a column or row that
+//! was already computed. Those are reused as-is (keeping the coordinate they
+//! were actually sampled at, so errors never accumulate) and only the gaps are
+//! sent to workers.
+
+use crate::colour::{ColourMapping, Colouring, Dither, InteriorMode, Layer, Lighting, PixelFormat};
+use crate::mandelbrot::{ViewMapping, INTERIOR, MIN_CUSTOM_DEGREE};
+use crate::messages::{Bailout, FormulaSequence, FrameRequest};
+
+/// How far (in new pixels) a retained sample may be from its ideal position
+const REUSE_TOLERANCE: f64 = 0.5;
+
+/// A finished frame kept for reprojecting the next one
+///
+/// Records everything that affects a pixel's colour besides its position.
+pub struct RetainedFrame {
+    width: u32,
+    height: u32,
+    palette_hashes: Vec<u64>,
+    interior: InteriorMode,
+    interior_colour: [u8; 3],
+    lighting: Option<Lighting>,
+    formula: FormulaSequence,
+    custom_formula: Option<String>,
+    bailout: Bailout,
+    colouring: Colouring,
+    colour_mapping: ColourMapping,
+    pixel_format: PixelFormat,
+    layers: Vec<Layer>,
+    dither: Dither,
+    max_iterations: u32,
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+    pixels: Vec<u8>,
+    iterations: Vec<f32>,
+}
+
+/// Sampling grid and reused data for a new frame
+pub struct ReprojectionPlan {
+    /// Real coordinate sampled by each column
+    pub xs: Vec<f64>,
+    /// Imaginary coordinate sampled by each row
+    pub ys: Vec<f64>,
+    /// Per-pixel flag: filled from the previous frame
+    pub reused: Vec<bool>,
+    /// Pixel data in the request's format, prefilled where reused
+    pub pixels: Vec<u8>,
+    /// Smooth iteration counts, prefilled where reused
+    pub iterations: Vec<f32>,
+}
+
+impl ReprojectionPlan {
+    /// Plan a frame, reusing `previous` where it is compatible
+    ///
+    /// `palette_hashes` identify the resolved palettes of the frame and its
+    /// layers, since a named palette can be redefined between frames.
+    pub fn new(request: &FrameRequest, palette_hashes: &[u64], previous: Option<&RetainedFrame>) -> Self {
+        let (width, height) = (request.width as usize, request.height as usize);
+        let bytes_per_pixel = request.pixel_format.bytes_per_pixel();
+        let view = ViewMapping::from_view(
+            request.width,
+            request.height,
+            request.center_x,
+            request.center_y,
+            request.zoom,
+        );
+        let ideal_xs: Vec<f64> = (0..request.width).map(|px| view.point(px, 0).0).collect();
+        let ideal_ys: Vec<f64> = (0..request.height).map(|py| view.point(0, py).1).collect();
+
+        let mut plan = Self {
+            xs: ideal_xs,
+            ys: ideal_ys,
+            reused: vec![false; width * height],
+            pixels: vec![0; width * height * bytes_per_pixel],
+            iterations: vec![0.0; width * height],
+        };
+
+        let previous = match previous {
+            Some(prev) if prev.is_compatible(request, palette_hashes) => prev,
+            _ => return plan,
+        };
+
+        let columns = match_axis(&previous.xs, &plan.xs, REUSE_TOLERANCE * view.x_scale);
+        let rows = match_axis(&previous.ys, &plan.ys, REUSE_TOLERANCE * view.y_scale);
+
+        for (x, col) in columns.iter().enumerate() {
+            if let Some(old_x) = col {
+                plan.xs[x] = previous.xs[*old_x];
+            }
+        }
+        for (y, row) in rows.iter().enumerate() {
+            if let Some(old_y) = row {
+                plan.ys[y] = previous.ys[*old_y];
+            }
+        }
+
+        // Points that were inside the set may escape with more iterations, and
+        // points that escaped late are inside the set with fewer. Only the
+        // smooth count is kept, which can sit a few iterations below the one
+        // the point escaped at, so anything near a lowered limit is redone.
+        // Interior colours other than a solid one come from the orbit at the
+        // limit, so change with it either way.
+        let refine_interior = request.max_iterations > previous.max_iterations
+            || (request.max_iterations != previous.max_iterations && request.interior != InteriorMode::Solid);
+        let lowered = request.max_iterations < previous.max_iterations;
+        let escape_limit = request.max_iterations as f32 - smoothing_margin(request);
+
+        for (y, row) in rows.iter().enumerate() {
+            let Some(old_y) = row else { continue };
+            for (x, col) in columns.iter().enumerate() {
+                let Some(old_x) = col else { continue };
+                let old = old_y * previous.width as usize + old_x;
+                let iteration = previous.iterations[old];
+                let stale = if iteration == INTERIOR { refine_interior } else { lowered && iteration > escape_limit };
+                if stale {
+                    continue;
+                }
+
+                let new = y * width + x;
+                plan.reused[new] = true;
+                plan.iterations[new] = iteration;
+                plan.pixels[new * bytes_per_pixel..(new + 1) * bytes_per_pixel]
+                    .copy_from_slice(&previous.pixels[old * bytes_per_pixel..(old + 1) * bytes_per_pixel]);
+            }
+        }
+
+        plan
+    }
+
+    /// Keep the finished frame for the next request
+    pub fn retain(
+        self,
+        request: &FrameRequest,
+        palette_hashes: Vec<u64>,
+        pixels: Vec<u8>,
+        iterations: Vec<f32>,
+    ) -> RetainedFrame {
+        RetainedFrame {
+            width: request.width,
+            height: request.height,
+            palette_hashes,
+            interior: request.interior,
+            interior_colour: request.interior_colour,
+            lighting: request.lighting,
+            formula: request.formula.clone(),
+            custom_formula: request.custom_formula.clone(),
+            bailout: request.bailout,
+            colouring: request.colouring,
+            colour_mapping: request.colour_mapping,
+            pixel_format: request.pixel_format,
+            layers: request.layers.clone(),
+            dither: request.dither,
+            max_iterations: request.max_iterations,
+            xs: self.xs,
+            ys: self.ys,
+            pixels,
+            iterations,
+        }
+    }
+}
+
+impl RetainedFrame {
+    /// Reused pixels keep their colour, so the colouring must be unchanged
+    ///
+    /// Histogram colouring depends on the whole frame's distribution, so its
+    /// colours can't be carried over to a different view.
+    fn is_compatible(&self, request: &FrameRequest, palette_hashes: &[u64]) -> bool {
+        self.width == request.width
+            && self.height == request.height
+            && request.colouring != Colouring::Histogram
+            && self.palette_hashes == palette_hashes
+            && self.interior == request.interior
+            && self.interior_colour == request.interior_colour
+            && self.lighting == request.lighting
+            && self.formula == request.formula
+            && self.custom_formula == request.custom_formula
+            && self.bailout == request.bailout
+            && self.colouring == request.colouring
+            && self.colour_mapping == request.colour_mapping
+            && self.pixel_format == request.pixel_format
+            && self.layers == request.layers
+            && self.dither == request.dither
+    }
+}
+
+/// How far a point's smooth iteration count can fall below the iteration it
+/// escaped at
+///
+/// The smooth count is `n + 1 - nu`. A point that only just escaped can end
+/// up with |z| near `radius^degree`, putting `nu` at up to
+/// `1 + log_degree(log2 radius)`; one more iteration covers the `+ c`.
+fn smoothing_margin(request: &FrameRequest) -> f32 {
+    let degree: f64 = if request.custom_formula.is_some() { MIN_CUSTOM_DEGREE } else { 2.0 };
+    (request.bailout.radius.log2().max(1.0).ln() / degree.ln() + 1.0) as f32
+}
+
+/// Match each ideal position to the nearest unused old sample within `tolerance`
+///
+/// Both slices are in ascending order, and so is the matching, so each old
+/// sample is reused at most once and the new axis stays sorted.
+fn match_axis(old: &[f64], ideal: &[f64], tolerance: f64) -> Vec<Option<usize>> {
+    let mut matches = vec![None; ideal.len()];
+    if old.is_empty() {
+        return matches;
+    }
+
+    let mut nearest = 0;
+    let mut next_free = 0;
+    for (i, &position) in ideal.iter().enumerate() {
+        while nearest + 1 < old.len()
+            && (old[nearest + 1] - position).abs() <= (old[nearest] - position).abs()
+        {
+            nearest += 1;
+        }
+        if nearest >= next_free && (old[nearest] - position).abs() < tolerance {
+            matches[i] = Some(nearest);
+            next_free = nearest + 1;
+        }
+    }
+
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_axis_zoom_in() {
+        // Zooming in by 2% around the centre reuses nearly every column once
+        let old: Vec<f64> = (0..100).map(|i| i as f64 - 50.0).collect();
+        let ideal: Vec<f64> = (0..100).map(|i| (i as f64 - 50.0) / 1.02).collect();
+        let matches = match_axis(&old, &ideal, 0.5 / 1.02);
+
+        let reused: Vec<usize> = matches.iter().flatten().copied().collect();
+        assert!(reused.len() >= 95);
+        assert!(reused.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    /// A frame four pixels wide and one high, retained with the given counts
+    fn retained(max_iterations: u32, interior: &str, iterations: Vec<f32>) -> RetainedFrame {
+        let request = frame_request(max_iterations, interior);
+        let pixels = vec![0; 4 * request.pixel_format.bytes_per_pixel()];
+        ReprojectionPlan::new(&request, &[1], None).retain(&request, vec![1], pixels, iterations)
+    }
+
+    fn frame_request(max_iterations: u32, interior: &str) -> FrameRequest {
+        serde_json::from_value(serde_json::json!({
+            "width": 4, "height": 1, "center_x": -0.5, "center_y": 0.0, "zoom": 1.0,
+            "max_iterations": max_iterations, "interior": interior
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_changed_limit_redoes_points_near_it() {
+        // A smooth count of 98 may have escaped at 99 or 100, so inside the set
+        // at a limit of 99; solid interior points stay inside
+        let previous = retained(100, "solid", vec![10.5, 96.0, 98.0, INTERIOR]);
+        let plan = ReprojectionPlan::new(&frame_request(99, "solid"), &[1], Some(&previous));
+        assert_eq!(plan.reused, [true, false, false, true]);
+
+        // Interior colours that come from the orbit change with the limit
+        let previous = retained(100, "angle", vec![10.5, 96.0, 98.0, INTERIOR]);
+        let plan = ReprojectionPlan::new(&frame_request(99, "angle"), &[1], Some(&previous));
+        assert_eq!(plan.reused, [true, false, false, false]);
+        let plan = ReprojectionPlan::new(&frame_request(100, "angle"), &[1], Some(&previous));
+        assert_eq!(plan.reused, [true; 4]);
+    }
+
+    #[test]
+    fn test_match_axis_disjoint() {
+        let old: Vec<f64> = (0..10).map(|i| i as f64).collect();
+        let ideal: Vec<f64> = (0..10).map(|i| i as f64 + 100.0).collect();
+        assert!(match_axis(&old, &ideal, 0.5).iter().all(Option::is_none));
+    }
+}
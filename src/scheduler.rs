@@ -0,0 +1,184 @@
+//! Fair scheduling of work units between client sessions
+//!
+//! Each session has its own queue, and sessions take turns by deficit round
+//! robin: a session sends units while its deficit covers their cost, and is
+//! topped up by a quantum scaled by the frame's priority each time round.
+//! Costs are in pixel-iterations, so a client rendering a large, deep view
+//! gets the same share of the workers as one rendering a small view, rather
+//! than crowding it out.
+
+use std::collections::VecDeque;
+
+/// Pixel-iterations a session at weight 1 may spend per turn
+pub const QUANTUM: f64 = 128.0 * 128.0 * 1000.0;
+
+struct Queued<T> {
+    unit: T,
+    cost: f64,
+    weight: f64,
+}
+
+/// Queued units of one client session
+struct SessionQueue<T> {
+    session: u64,
+    deficit: f64,
+    units: VecDeque<Queued<T>>,
+}
+
+/// Work units of every session, handed out fairly between sessions and in
+/// order within each
+pub struct Scheduler<T> {
+    sessions: VecDeque<SessionQueue<T>>,  // Sessions with queued work, in turn order
+}
+
+impl<T> Scheduler<T> {
+    pub fn new() -> Self {
+        Self { sessions: VecDeque::new() }
+    }
+
+    /// Queue a unit behind the session's others; a newly active session
+    /// takes its turn after the sessions already waiting
+    pub fn push(&mut self, session: u64, unit: T, cost: f64, weight: f64) {
+        let queued = Queued { unit, cost, weight };
+        match self.sessions.iter_mut().find(|queue| queue.session == session) {
+            Some(queue) => queue.units.push_back(queued),
+            None => self.sessions.push_back(SessionQueue {
+                session,
+                deficit: 0.0,
+                units: VecDeque::from([queued]),
+            }),
+        }
+    }
+
+    /// Queue a unit to go next for its session, e.g. one returned by a lost
+    /// worker; a session with no other work goes first
+    pub fn push_front(&mut self, session: u64, unit: T, cost: f64, weight: f64) {
+        let queued = Queued { unit, cost, weight };
+        match self.sessions.iter_mut().find(|queue| queue.session == session) {
+            Some(queue) => queue.units.push_front(queued),
+            None => self.sessions.push_front(SessionQueue {
+                session,
+                deficit: 0.0,
+                units: VecDeque::from([queued]),
+            }),
+        }
+    }
+
+    /// Take the next unit, discarding any that are no longer `wanted`
+    pub fn next(&mut self, mut wanted: impl FnMut(&T) -> bool) -> Option<T> {
+        self.skip_idle_rounds(&mut wanted);
+        loop {
+            let queue = self.sessions.front_mut()?;
+            while queue.units.front().is_some_and(|queued| !wanted(&queued.unit)) {
+                queue.units.pop_front();
+            }
+            let Some(head) = queue.units.front() else {
+                // Sessions with nothing queued drop out and don't bank a deficit
+                self.sessions.pop_front();
+                continue;
+            };
+
+            if queue.deficit >= head.cost {
+                queue.deficit -= head.cost;
+                let unit = queue.units.pop_front().map(|queued| queued.unit);
+                if queue.units.is_empty() {
+                    self.sessions.pop_front();
+                }
+                return unit;
+            }
+
+            // Out of deficit: top up and let the next session have its turn
+            queue.deficit += QUANTUM * head.weight;
+            self.sessions.rotate_left(1);
+        }
+    }
+
+    /// Top up every session by the rounds in which none could afford its
+    /// next unit, all at once
+    ///
+    /// Costs grow with the iteration limit, so a deep frame's tile can be
+    /// worth thousands of quanta. Leaving one round to go keeps the turn order
+    /// the same as topping up a round at a time.
+    fn skip_idle_rounds(&mut self, wanted: &mut impl FnMut(&T) -> bool) {
+        self.sessions.retain_mut(|queue| {
+            while queue.units.front().is_some_and(|queued| !wanted(&queued.unit)) {
+                queue.units.pop_front();
+            }
+            !queue.units.is_empty()
+        });
+        let rounds = self
+            .sessions
+            .iter()
+            .map(|queue| ((queue.units[0].cost - queue.deficit) / (QUANTUM * queue.units[0].weight)).ceil())
+            .fold(f64::INFINITY, f64::min);
+        if rounds.is_finite() && rounds > 1.0 {
+            for queue in self.sessions.iter_mut() {
+                queue.deficit += (rounds - 1.0) * QUANTUM * queue.units[0].weight;
+            }
+        }
+    }
+}
+
+impl<T> Default for Scheduler<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sessions of the first `count` units handed out
+    fn order(scheduler: &mut Scheduler<(u64, usize)>, count: usize) -> Vec<u64> {
+        (0..count).map(|_| scheduler.next(|_| true).unwrap().0).collect()
+    }
+
+    #[test]
+    fn test_sessions_share_by_cost() {
+        // A heavy session queued first doesn't hold up a light one; each gets
+        // about the same pixel-iterations
+        let mut scheduler = Scheduler::new();
+        for i in 0..20 {
+            scheduler.push(1, (1, i), 4.0 * QUANTUM, 1.0);
+        }
+        for i in 0..20 {
+            scheduler.push(2, (2, i), QUANTUM, 1.0);
+        }
+        let served = order(&mut scheduler, 10);
+        assert_eq!(served.iter().filter(|&&session| session == 1).count(), 2);
+        assert_eq!(served.iter().filter(|&&session| session == 2).count(), 8);
+    }
+
+    #[test]
+    fn test_priority_and_requeue() {
+        let mut scheduler = Scheduler::new();
+        for i in 0..10 {
+            scheduler.push(1, (1, i), QUANTUM, 1.0);
+            scheduler.push(2, (2, i), QUANTUM, 4.0);
+        }
+        let served = order(&mut scheduler, 10);
+        assert_eq!(served.iter().filter(|&&session| session == 2).count(), 8);
+
+        // A requeued unit goes next for its session, and unwanted units are skipped
+        let mut scheduler = Scheduler::new();
+        for i in 0..3 {
+            scheduler.push(1, (1, i), QUANTUM, 1.0);
+        }
+        scheduler.push_front(1, (1, 99), QUANTUM, 1.0);
+        assert_eq!(scheduler.next(|_| true), Some((1, 99)));
+        assert_eq!(scheduler.next(|unit| unit.1 != 0), Some((1, 1)));
+        assert_eq!(scheduler.next(|_| false), None);
+        assert_eq!(scheduler.next(|_| true), None);
+    }
+
+    #[test]
+    fn test_deep_units_are_reached_at_once() {
+        // Millions of rounds' worth of cost at low priority; the cheaper unit
+        // still goes first, and neither takes a round at a time to reach
+        let mut scheduler = Scheduler::new();
+        scheduler.push(1, (1, 0), 4e9 * QUANTUM, 0.25);
+        scheduler.push(2, (2, 0), 1e9 * QUANTUM, 0.25);
+        assert_eq!(order(&mut scheduler, 2), [2, 1]);
+    }
+}
@@ -1,14 +1,19 @@
-/// Worker module - connects to coordinator, renders strips
+//! Worker module - connects to coordinator, renders strips
 
 use base64::Engine;
 use futures_util::{SinkExt, StreamExt};
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 
-use crate::colour::Palette;
-use crate::mandelbrot::render_strip;
+use crate::colour::{ColourMapping, Dither, InteriorMode, Palette, PixelFormat, Rgb, PALETTE_SIZE};
+use crate::mandelbrot::{
+    render_strip, render_strip_iterations, render_strip_pass, render_strip_reprojected, Palettes, PROGRESSIVE_STEPS,
+};
 use crate::messages::*;
+use crate::palette_cache::PaletteCache;
 
 /// Heartbeat interval
 const HEARTBEAT_INTERVAL_SECS: u64 = 10;
@@ -16,11 +21,23 @@ const HEARTBEAT_INTERVAL_SECS: u64 = 10;
 /// Reconnection delay
 const RECONNECT_DELAY_SECS: u64 = 5;
 
+/// Work for the render task, done one at a time in arrival order
+enum Job {
+    Render(Box<RenderStripRequest>, Palettes),
+    Profile { width: u32, height: u32 },
+}
+
 /// Worker state
 pub struct Worker {
     pub worker_id: String,
     pub coordinator_url: String,
-    pub palette: Vec<(u8, u8, u8)>,
+    /// Generated palette tables by definition hash, shared across strips and
+    /// evicted in step with the coordinator's record of them
+    palettes: RwLock<PaletteCache<Arc<Vec<Rgb>>>>,
+    /// Frames the coordinator has cancelled; their remaining tiles are skipped
+    cancelled: RwLock<HashSet<u64>>,
+    /// Frame of the tile being rendered, and the flag that abandons it
+    rendering: RwLock<Option<(u64, Arc<AtomicBool>)>>,
 }
 
 impl Worker {
@@ -28,10 +45,47 @@ impl Worker {
         Self {
             worker_id: uuid::Uuid::new_v4().to_string(),
             coordinator_url,
-            palette: Palette::default().generate(2048),
+            palettes: RwLock::new(PaletteCache::new()),
+            cancelled: RwLock::new(HashSet::new()),
+            rendering: RwLock::new(None),
+        }
+    }
+
+    /// Look up the palette table a strip refers to, defining it if the strip
+    /// brought it along
+    fn palette(&self, hash: u64, definitions: &[(u64, Palette)]) -> Arc<Vec<Rgb>> {
+        let mut palettes = self.palettes.write().unwrap();
+        if let Some(table) = palettes.get(hash) {
+            return Arc::clone(table);
+        }
+        match definitions.iter().find(|(known, _)| *known == hash) {
+            Some((_, palette)) => {
+                tracing::debug!("Defining palette {:016x}", hash);
+                let table = Arc::new(palette.generate(PALETTE_SIZE));
+                palettes.insert(hash, Arc::clone(&table));
+                table
+            }
+            None => {
+                tracing::warn!("Palette {:016x} was never defined, using the default", hash);
+                Arc::new(Palette::default().generate(PALETTE_SIZE))
+            }
         }
     }
 
+    /// Look up the frame palette and layer palettes of a strip
+    ///
+    /// Called as each strip arrives, in the order of `palette_hashes`, which
+    /// is the order the coordinator updates its copy of the cache in.
+    fn palettes(&self, req: &RenderStripRequest, definitions: &[(u64, Palette)]) -> Palettes {
+        let frame = self.palette(req.palette_hash, definitions);
+        let layers = req
+            .layers
+            .iter()
+            .map(|layer| layer.palette_hash.map_or_else(|| Arc::clone(&frame), |hash| self.palette(hash, definitions)))
+            .collect();
+        Palettes { frame, layers }
+    }
+
     /// Run the worker - connects to coordinator and processes work
     pub async fn run(self: Arc<Self>) {
         loop {
@@ -51,12 +105,15 @@ impl Worker {
         }
     }
 
-    async fn connect_and_work(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn connect_and_work(self: &Arc<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let (ws_stream, _) = connect_async(&self.coordinator_url).await?;
-        let (mut sender, mut receiver) = ws_stream.split();
+        let (mut sender, receiver) = ws_stream.split();
 
         tracing::info!("Connected to coordinator");
 
+        // The coordinator only knows what it sent on this connection
+        self.palettes.write().unwrap().clear();
+
         // Register with coordinator
         let register_msg = WorkerToCoordinator::Register {
             worker_id: self.worker_id.clone(),
@@ -64,13 +121,11 @@ impl Worker {
         sender.send(Message::Text(serde_json::to_string(&register_msg)?)).await?;
 
         // Spawn heartbeat task
-        let worker_id = self.worker_id.clone();
         let heartbeat_sender = sender.reunite(receiver).expect("reunite failed");
         let (mut sender, mut receiver) = heartbeat_sender.split();
 
         let (heartbeat_tx, mut heartbeat_rx) = tokio::sync::mpsc::channel::<()>(1);
 
-        let heartbeat_worker_id = worker_id.clone();
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(HEARTBEAT_INTERVAL_SECS));
             loop {
@@ -113,6 +168,27 @@ impl Worker {
             }
         });
 
+        // Render task - tiles are rendered one at a time in arrival order, while
+        // this loop carries on reading messages so cancellations get through.
+        // Profiles go through the same queue so they never share the CPU with
+        // a render. Unbounded since the coordinator limits the tiles a worker holds.
+        let (render_tx, mut render_rx) = tokio::sync::mpsc::unbounded_channel::<Job>();
+        let worker = Arc::clone(self);
+        let render_send_tx = send_tx.clone();
+        tokio::spawn(async move {
+            while let Some(job) = render_rx.recv().await {
+                match job {
+                    Job::Render(req, palettes) => worker.render(req, palettes, &render_send_tx).await,
+                    Job::Profile { width, height } => worker.profile(width, height, &render_send_tx).await,
+                }
+                // Every tile of a frame arrives before its cancel, so once the
+                // queue is empty no cancelled tiles are left to skip
+                if render_rx.is_empty() {
+                    worker.cancelled.write().unwrap().clear();
+                }
+            }
+        });
+
         // Process messages from coordinator
         while let Some(msg) = receiver.next().await {
             let msg = match msg {
@@ -121,7 +197,7 @@ impl Worker {
                     tracing::info!("Coordinator closed connection");
                     break;
                 }
-                Ok(Message::Ping(data)) => {
+                Ok(Message::Ping(_)) => {
                     // Pong is handled automatically by tungstenite
                     continue;
                 }
@@ -146,19 +222,17 @@ impl Worker {
                 }
 
                 CoordinatorToWorker::RunProfile { width, height } => {
-                    tracing::debug!("Running profile {}x{}", width, height);
-                    let compute_ms = self.run_profile(width, height);
-                    let response = WorkerToCoordinator::ProfileResult {
-                        worker_id: self.worker_id.clone(),
-                        compute_ms,
-                    };
-                    let _ = send_tx.send(response).await;
+                    let _ = render_tx.send(Job::Profile { width, height });
                 }
 
-                CoordinatorToWorker::RenderStrip(req) => {
-                    tracing::debug!("Rendering strip {} y={}..{}", req.frame_id, req.y_start, req.y_end);
-                    let result = self.render_strip_request(&req);
-                    let _ = send_tx.send(WorkerToCoordinator::StripResult(result)).await;
+                CoordinatorToWorker::RenderStrip { strip, palettes } => {
+                    let palettes = self.palettes(&strip, &palettes);
+                    let _ = render_tx.send(Job::Render(strip, palettes));
+                }
+
+                CoordinatorToWorker::CancelFrame { frame_id } => {
+                    tracing::debug!("Cancelling frame {}", frame_id);
+                    self.cancel_frame(frame_id);
                 }
             }
         }
@@ -169,57 +243,197 @@ impl Worker {
         Ok(())
     }
 
-    /// Run a profiling computation
-    fn run_profile(&self, width: u32, height: u32) -> u64 {
-        let start = Instant::now();
+    /// Run a profile on the blocking pool and report how long it took
+    async fn profile(&self, width: u32, height: u32, send_tx: &tokio::sync::mpsc::Sender<WorkerToCoordinator>) {
+        tracing::debug!("Running profile {}x{}", width, height);
+        let compute_ms = match tokio::task::spawn_blocking(move || run_profile(width, height)).await {
+            Ok(compute_ms) => compute_ms,
+            Err(e) => {
+                tracing::error!("Profile task failed: {}", e);
+                return;
+            }
+        };
+        let response = WorkerToCoordinator::ProfileResult {
+            worker_id: self.worker_id.clone(),
+            compute_ms,
+        };
+        let _ = send_tx.send(response).await;
+    }
 
-        // Fixed profile area - standard Mandelbrot view
-        let _ = render_strip(
-            width,
-            0,
-            height,
-            height,
-            -0.5,
-            0.0,
-            1.0,
-            256,
-            &self.palette,
-            false,
+    /// Stop rendering a frame's tiles, including any already queued
+    fn cancel_frame(&self, frame_id: u64) {
+        // Recorded before checking the running tile, which `render` registers
+        // before checking the record, so a tile starting meanwhile is caught
+        self.cancelled.write().unwrap().insert(frame_id);
+        if let Some((rendering, cancel)) = self.rendering.read().unwrap().as_ref() {
+            if *rendering == frame_id {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Render a tile on the blocking pool and send its results, unless its
+    /// frame is cancelled before or during the render
+    async fn render(
+        self: &Arc<Self>,
+        req: Box<RenderStripRequest>,
+        palettes: Palettes,
+        send_tx: &tokio::sync::mpsc::Sender<WorkerToCoordinator>,
+    ) {
+        let cancel = Arc::new(AtomicBool::new(false));
+        *self.rendering.write().unwrap() = Some((req.frame_id, Arc::clone(&cancel)));
+        if self.cancelled.read().unwrap().contains(&req.frame_id) {
+            cancel.store(true, Ordering::Relaxed);
+        }
+
+        tracing::debug!(
+            "Rendering tile {} x={}..{} y={}..{}",
+            req.frame_id, req.x_start, req.x_end, req.y_start, req.y_end
         );
+        let worker = Arc::clone(self);
+        let blocking_tx = send_tx.clone();
+        let (frame_id, x_start, y_start) = (req.frame_id, req.x_start, req.y_start);
+        let rendered = tokio::task::spawn_blocking(move || {
+            if req.progressive {
+                worker.render_progressive_request(&req, &palettes, &blocking_tx, &cancel)
+            } else {
+                match worker.render_strip_request(&req, &palettes, &cancel) {
+                    Some(result) => blocking_tx.blocking_send(WorkerToCoordinator::StripResult(result)).is_ok(),
+                    None => false,
+                }
+            }
+        })
+        .await;
+        *self.rendering.write().unwrap() = None;
 
-        start.elapsed().as_millis() as u64
+        // The coordinator holds the tile's slot until it hears either way
+        let worker_id = self.worker_id.clone();
+        let message = match rendered {
+            Ok(true) => return,
+            Ok(false) => {
+                tracing::debug!("Abandoned tile of frame {}", frame_id);
+                WorkerToCoordinator::StripAbandoned { worker_id, frame_id, x_start, y_start }
+            }
+            Err(e) => {
+                tracing::error!("Render task failed: {}", e);
+                WorkerToCoordinator::StripFailed { worker_id, frame_id, x_start, y_start, message: e.to_string() }
+            }
+        };
+        let _ = send_tx.send(message).await;
     }
 
-    /// Render a strip request
-    fn render_strip_request(&self, req: &RenderStripRequest) -> StripResult {
+    /// Render a strip request, or `None` if it was cancelled part way
+    fn render_strip_request(
+        &self,
+        req: &RenderStripRequest,
+        palettes: &Palettes,
+        cancel: &AtomicBool,
+    ) -> Option<StripResult> {
         let start = Instant::now();
 
-        // Generate palette based on request
-        let palette = req.palette.generate(2048);
-
-        let pixels = render_strip(
-            req.width,
-            req.y_start,
-            req.y_end,
-            req.total_height,
-            req.center_x,
-            req.center_y,
-            req.zoom,
-            req.max_iterations,
-            &palette,
-            req.colour_interior,
-        );
+        let (pixels, iterations) = match &req.reprojection {
+            Some(reprojection) => {
+                let (pixels, iterations) = render_strip_reprojected(req, reprojection, palettes, cancel)?;
+                (pixels, Some(encode_iterations(&iterations)))
+            }
+            None => {
+                let (pixels, iterations) = render_strip_iterations(req, palettes, cancel)?;
+                (pixels, req.return_iterations.then(|| encode_iterations(&iterations)))
+            }
+        };
 
         let compute_ms = start.elapsed().as_millis() as u64;
         let data = base64::engine::general_purpose::STANDARD.encode(&pixels);
 
-        StripResult {
+        Some(StripResult {
             worker_id: self.worker_id.clone(),
             frame_id: req.frame_id,
+            x_start: req.x_start,
+            x_end: req.x_end,
             y_start: req.y_start,
             y_end: req.y_end,
+            pass: 0,
+            last_pass: true,
             compute_ms,
             data,
+            iterations,
+        })
+    }
+
+    /// Render a strip request coarse-to-fine, sending each pass's new samples
+    ///
+    /// Runs on the blocking pool. Returns whether every pass was sent.
+    fn render_progressive_request(
+        &self,
+        req: &RenderStripRequest,
+        palettes: &Palettes,
+        send_tx: &tokio::sync::mpsc::Sender<WorkerToCoordinator>,
+        cancel: &AtomicBool,
+    ) -> bool {
+        let start = Instant::now();
+
+        for pass in 0..PROGRESSIVE_STEPS.len() {
+            let Some((pixels, iterations)) = render_strip_pass(req, palettes, pass, cancel) else {
+                return false;
+            };
+
+            // Histogram previews are recoloured from every pass's iterations
+            let result = StripResult {
+                worker_id: self.worker_id.clone(),
+                frame_id: req.frame_id,
+                x_start: req.x_start,
+                x_end: req.x_end,
+                y_start: req.y_start,
+                y_end: req.y_end,
+                pass: pass as u32,
+                last_pass: pass + 1 == PROGRESSIVE_STEPS.len(),
+                compute_ms: start.elapsed().as_millis() as u64,
+                data: base64::engine::general_purpose::STANDARD.encode(&pixels),
+                iterations: req.return_iterations.then(|| encode_iterations(&iterations)),
+            };
+            if send_tx.blocking_send(WorkerToCoordinator::StripResult(result)).is_err() {
+                return false;
+            }
         }
+        true
     }
 }
+
+/// Run a profiling computation
+fn run_profile(width: u32, height: u32) -> u64 {
+    let start = Instant::now();
+
+    // Fixed profile area - standard Mandelbrot view
+    let palette = Palette::default();
+    let req = RenderStripRequest {
+        frame_id: 0,
+        width,
+        x_start: 0,
+        x_end: width,
+        y_start: 0,
+        y_end: height,
+        total_height: height,
+        center_x: -0.5,
+        center_y: 0.0,
+        zoom: 1.0,
+        max_iterations: 256,
+        palette_hash: palette.hash_key(),
+        interior: InteriorMode::Solid,
+        interior_colour: [0; 3],
+        progressive: false,
+        reprojection: None,
+        lighting: None,
+        projection: Projection::Rectilinear,
+        formula: FormulaSequence::default(),
+        program: None,
+        bailout: Bailout::default(),
+        return_iterations: false,
+        colour_mapping: ColourMapping::default(),
+        pixel_format: PixelFormat::Rgb8,
+        layers: Vec::new(),
+        dither: Dither::None,
+    };
+    let _ = render_strip(&req, &Palettes::from(palette.generate(PALETTE_SIZE)));
+
+    start.elapsed().as_millis() as u64
+}
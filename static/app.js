@@ -26,13 +26,35 @@ class FractalZoomer {
         this.zoomSpeed = 1.02;
         this.maxIterations = 500;
         this.palette = 'fire';
-        this.colourInterior = false;
+        this.gradient = '';
+        this.gradientSpace = 'oklab';
+        this.interior = 'solid';
+        this.interiorColour = '#000000';
+        this.progressive = false;
+        this.reproject = true;
+        this.lighting = 'none';
+        this.lightAngle = 45;
+        this.projection = 'rectilinear';
+        this.formula = 'mandelbrot';
+        this.customFormula = '';
+        this.bailoutRadius = 256;
+        this.bailoutNorm = 'euclidean';
+        this.colouring = 'smooth';
+        this.transfer = 'linear';
+        this.density = 0.1;
+        this.colourCycle = 0;  // Palette offset added per frame
+        this.colourOffset = 0;
+        this.pixelFormat = 'rgb8';
+        this.dither = 'none';
+        this.layers = 'none';
+        this.lastFrame = null;  // Kept at full precision for saving
 
         // Connection state
         this.socket = null;
         this.connected = false;
         this.running = false;
         this.pendingFrame = false;
+        this.frameId = null;  // Set once the coordinator accepts the frame in flight
 
         // Stats
         this.frameCount = 0;
@@ -48,17 +70,118 @@ class FractalZoomer {
         this.frameDisplay = document.getElementById('frame');
         this.startBtn = document.getElementById('startBtn');
         this.stopBtn = document.getElementById('stopBtn');
+        this.saveBtn = document.getElementById('saveBtn');
+        this.pixelFormatSelect = document.getElementById('pixelFormat');
+        this.ditherSelect = document.getElementById('dither');
         this.maxIterInput = document.getElementById('maxIter');
         this.zoomSpeedInput = document.getElementById('zoomSpeed');
         this.paletteSelect = document.getElementById('palette');
-        this.colourInteriorCheckbox = document.getElementById('colourInterior');
+        this.gradientInput = document.getElementById('gradient');
+        this.gradientSpaceSelect = document.getElementById('gradientSpace');
+        this.previewVisionSelect = document.getElementById('previewVision');
+        this.palettePreview = document.getElementById('palettePreview');
+        this.paletteImageInput = document.getElementById('paletteImage');
+        this.extractStopsInput = document.getElementById('extractStops');
+        this.extractOrderSelect = document.getElementById('extractOrder');
+        this.extractBtn = document.getElementById('extractBtn');
+        this.interiorSelect = document.getElementById('interior');
+        this.interiorColourInput = document.getElementById('interiorColour');
+        this.progressiveCheckbox = document.getElementById('progressive');
+        this.reprojectCheckbox = document.getElementById('reproject');
+        this.lightingSelect = document.getElementById('lighting');
+        this.lightAngleInput = document.getElementById('lightAngle');
+        this.projectionSelect = document.getElementById('projection');
+        this.formulaSelect = document.getElementById('formula');
+        this.customFormulaInput = document.getElementById('customFormula');
+        this.formulaErrorDisplay = document.getElementById('formulaError');
+        this.bailoutRadiusInput = document.getElementById('bailoutRadius');
+        this.bailoutNormSelect = document.getElementById('bailoutNorm');
+        this.colouringSelect = document.getElementById('colouring');
+        this.transferSelect = document.getElementById('transfer');
+        this.densityInput = document.getElementById('density');
+        this.colourCycleInput = document.getElementById('colourCycle');
+        this.layersSelect = document.getElementById('layers');
 
         this.setupEventListeners();
+        this.loadImportedPalettes();
+        this.updatePalettePreview();
+    }
+
+    updatePalettePreview() {
+        // Read straight from the inputs so the preview follows edits before Start
+        this.palette = this.paletteSelect.value;
+        this.gradient = this.gradientInput.value.trim();
+        this.gradientSpace = this.gradientSpaceSelect.value;
+        const palette = this.paletteRequest();
+        const params = new URLSearchParams({
+            palette: typeof palette === 'string' ? palette : JSON.stringify(palette),
+            width: 256,
+            height: 16
+        });
+        if (this.previewVisionSelect.value) {
+            params.set('vision', this.previewVisionSelect.value);
+        }
+        this.palettePreview.src = `/palettes/preview?${params}`;
+    }
+
+    async loadImportedPalettes() {
+        // Palettes imported on the coordinator are selectable by name
+        try {
+            const response = await fetch('/palettes');
+            const names = await response.json();
+            for (const name of names) {
+                const option = document.createElement('option');
+                option.value = name;
+                option.textContent = name;
+                this.paletteSelect.appendChild(option);
+            }
+        } catch (e) {
+            console.warn('Could not load imported palettes:', e);
+        }
+    }
+
+    async extractPalette() {
+        // The coordinator clusters the image's colours and keeps the result as a named palette
+        const file = this.paletteImageInput.files[0];
+        if (!file) return;
+        const name = file.name.replace(/\.[^.]*$/, '');
+        const params = new URLSearchParams({
+            stops: this.extractStopsInput.value,
+            order: this.extractOrderSelect.value
+        });
+        try {
+            const response = await fetch(`/palettes/extract/${encodeURIComponent(name)}?${params}`, {
+                method: 'POST',
+                body: file
+            });
+            if (!response.ok) {
+                throw new Error(await response.text());
+            }
+            if (![...this.paletteSelect.options].some(o => o.value === name)) {
+                const option = document.createElement('option');
+                option.value = name;
+                option.textContent = name;
+                this.paletteSelect.appendChild(option);
+            }
+            this.paletteSelect.value = name;
+            this.updatePalettePreview();
+        } catch (e) {
+            console.warn('Could not extract palette:', e);
+        }
     }
 
     setupEventListeners() {
         this.startBtn.addEventListener('click', () => this.start());
         this.stopBtn.addEventListener('click', () => this.stop());
+        this.saveBtn.addEventListener('click', () => this.saveFrame());
+
+        this.pixelFormatSelect.addEventListener('change', (e) => {
+            this.pixelFormat = e.target.value;
+        });
+
+        this.ditherSelect.addEventListener('change', (e) => {
+            this.dither = e.target.value;
+        });
 
         this.maxIterInput.addEventListener('change', (e) => {
             this.maxIterations = parseInt(e.target.value) || 500;
@@ -68,12 +191,84 @@ class FractalZoomer {
             this.zoomSpeed = parseFloat(e.target.value) || 1.02;
         });
 
-        this.paletteSelect.addEventListener('change', (e) => {
-            this.palette = e.target.value;
+        this.paletteSelect.addEventListener('change', () => this.updatePalettePreview());
+        this.gradientInput.addEventListener('change', () => this.updatePalettePreview());
+        this.gradientSpaceSelect.addEventListener('change', () => this.updatePalettePreview());
+        this.previewVisionSelect.addEventListener('change', () => this.updatePalettePreview());
+        this.extractBtn.addEventListener('click', () => this.extractPalette());
+
+        // Settings changes make the frame in flight stale. Field listeners run
+        // first, so the replacement frame is requested with the new settings.
+        const previewOnly = new Set(['previewVision', 'paletteImage', 'extractStops', 'extractOrder']);
+        document.getElementById('controls').addEventListener('change', (e) => {
+            if (!previewOnly.has(e.target.id)) {
+                this.cancelFrame();
+            }
+        });
+
+        this.interiorSelect.addEventListener('change', (e) => {
+            this.interior = e.target.value;
         });
 
-        this.colourInteriorCheckbox.addEventListener('change', (e) => {
-            this.colourInterior = e.target.checked;
+        this.interiorColourInput.addEventListener('change', (e) => {
+            this.interiorColour = e.target.value;
+        });
+
+        this.progressiveCheckbox.addEventListener('change', (e) => {
+            this.progressive = e.target.checked;
+        });
+
+        this.reprojectCheckbox.addEventListener('change', (e) => {
+            this.reproject = e.target.checked;
+        });
+
+        this.lightingSelect.addEventListener('change', (e) => {
+            this.lighting = e.target.value;
+        });
+
+        this.lightAngleInput.addEventListener('change', (e) => {
+            this.lightAngle = parseFloat(e.target.value) || 45;
+        });
+
+        this.projectionSelect.addEventListener('change', (e) => {
+            this.projection = e.target.value;
+        });
+
+        this.formulaSelect.addEventListener('change', (e) => {
+            this.formula = e.target.value;
+        });
+
+        this.customFormulaInput.addEventListener('change', (e) => {
+            this.customFormula = e.target.value.trim();
+            this.formulaErrorDisplay.textContent = '';
+        });
+
+        this.bailoutRadiusInput.addEventListener('change', (e) => {
+            this.bailoutRadius = parseFloat(e.target.value) || 256;
+        });
+
+        this.bailoutNormSelect.addEventListener('change', (e) => {
+            this.bailoutNorm = e.target.value;
+        });
+
+        this.colouringSelect.addEventListener('change', (e) => {
+            this.colouring = e.target.value;
+        });
+
+        this.transferSelect.addEventListener('change', (e) => {
+            this.transfer = e.target.value;
+        });
+
+        this.densityInput.addEventListener('change', (e) => {
+            this.density = parseFloat(e.target.value) || 0.1;
+        });
+
+        this.colourCycleInput.addEventListener('change', (e) => {
+            this.colourCycle = parseFloat(e.target.value) || 0;
+        });
+
+        this.layersSelect.addEventListener('change', (e) => {
+            this.layers = e.target.value;
         });
     }
 
@@ -86,7 +281,27 @@ class FractalZoomer {
         this.maxIterations = parseInt(this.maxIterInput.value) || 500;
         this.zoomSpeed = parseFloat(this.zoomSpeedInput.value) || 1.02;
         this.palette = this.paletteSelect.value;
-        this.colourInterior = this.colourInteriorCheckbox.checked;
+        this.gradient = this.gradientInput.value.trim();
+        this.gradientSpace = this.gradientSpaceSelect.value;
+        this.interior = this.interiorSelect.value;
+        this.interiorColour = this.interiorColourInput.value;
+        this.progressive = this.progressiveCheckbox.checked;
+        this.reproject = this.reprojectCheckbox.checked;
+        this.lighting = this.lightingSelect.value;
+        this.lightAngle = parseFloat(this.lightAngleInput.value) || 45;
+        this.projection = this.projectionSelect.value;
+        this.formula = this.formulaSelect.value;
+        this.customFormula = this.customFormulaInput.value.trim();
+        this.formulaErrorDisplay.textContent = '';
+        this.bailoutRadius = parseFloat(this.bailoutRadiusInput.value) || 256;
+        this.bailoutNorm = this.bailoutNormSelect.value;
+        this.colouring = this.colouringSelect.value;
+        this.pixelFormat = this.pixelFormatSelect.value;
+        this.dither = this.ditherSelect.value;
+        this.transfer = this.transferSelect.value;
+        this.density = parseFloat(this.densityInput.value) || 0.1;
+        this.colourCycle = parseFloat(this.colourCycleInput.value) || 0;
+        this.layers = this.layersSelect.value;
 
         // Connect to coordinator
         await this.connect();
@@ -160,13 +375,26 @@ class FractalZoomer {
             case 'frame':
                 this.handleFrame(message);
                 break;
+            case 'frame_pass':
+                // Coarse preview - draw it, but keep waiting for the full frame
+                this.drawFrame(message);
+                break;
             case 'status':
                 this.handleStatus(message);
                 break;
+            case 'frame_accepted':
+                this.frameId = message.frame_id;
+                break;
+            case 'frame_cancelled':
+                this.pendingFrame = false;
+                break;
             case 'error':
                 console.error('Coordinator error:', message.message);
                 this.pendingFrame = false;
                 break;
+            case 'formula_error':
+                this.handleFormulaError(message);
+                break;
         }
     }
 
@@ -174,32 +402,126 @@ class FractalZoomer {
         this.pendingFrame = false;
         this.lastRenderMs = frame.render_ms;
 
-        // Decode base64 RGB data
-        const binaryString = atob(frame.data);
+        this.drawFrame(frame);
+        this.lastFrame = frame;
+
+        // Update stats
+        this.frameCount++;
+        this.frameTimestamps.push(performance.now());
+        this.updateStats();
+
+        // Increase zoom for next frame
+        this.zoom *= this.zoomSpeed;
+    }
+
+    decodeBase64(data) {
+        const binaryString = atob(data);
         const bytes = new Uint8Array(binaryString.length);
         for (let i = 0; i < binaryString.length; i++) {
             bytes[i] = binaryString.charCodeAt(i);
         }
+        return bytes;
+    }
+
+    drawFrame(frame) {
+        const bytes = this.decodeBase64(frame.data);
+        const view = new DataView(bytes.buffer);
+        const pixels = frame.width * frame.height;
+
+        // The canvas is 8-bit, so wider formats are reduced here
+        const channel = {
+            rgb8: (i) => bytes[i],
+            rgb16: (i) => view.getUint16(i * 2, true) >> 8,
+            rgb_f32: (i) => {
+                // Clip linear light and re-encode as sRGB
+                const c = Math.min(Math.max(view.getFloat32(i * 4, true), 0), 1);
+                const s = c <= 0.0031308 ? c * 12.92 : 1.055 * Math.pow(c, 1 / 2.4) - 0.055;
+                return Math.round(s * 255);
+            }
+        }[frame.format || 'rgb8'];
 
         // Create ImageData and copy RGB to RGBA
         const imageData = this.ctx.createImageData(frame.width, frame.height);
-        for (let i = 0, j = 0; i < bytes.length; i += 3, j += 4) {
-            imageData.data[j] = bytes[i];         // R
-            imageData.data[j + 1] = bytes[i + 1]; // G
-            imageData.data[j + 2] = bytes[i + 2]; // B
-            imageData.data[j + 3] = 255;          // A
+        for (let p = 0; p < pixels; p++) {
+            imageData.data[p * 4] = channel(p * 3);         // R
+            imageData.data[p * 4 + 1] = channel(p * 3 + 1); // G
+            imageData.data[p * 4 + 2] = channel(p * 3 + 2); // B
+            imageData.data[p * 4 + 3] = 255;                // A
         }
 
         // Draw to canvas
         this.ctx.putImageData(imageData, 0, 0);
+    }
 
-        // Update stats
-        this.frameCount++;
-        this.frameTimestamps.push(performance.now());
-        this.updateStats();
+    saveFrame() {
+        // Save at the frame's own precision: PPM for 8/16-bit, PFM for float
+        const frame = this.lastFrame;
+        if (!frame) {
+            return;
+        }
+        const bytes = this.decodeBase64(frame.data);
+        const { width, height } = frame;
+        let header, body, extension;
+
+        if (frame.format === 'rgb_f32') {
+            // PFM rows run bottom to top; a negative scale means little-endian
+            header = `PF\n${width} ${height}\n-1.0\n`;
+            body = new Uint8Array(bytes.length);
+            const rowBytes = width * 12;
+            for (let y = 0; y < height; y++) {
+                body.set(bytes.subarray(y * rowBytes, (y + 1) * rowBytes), (height - 1 - y) * rowBytes);
+            }
+            extension = 'pfm';
+        } else if (frame.format === 'rgb16') {
+            // 16-bit PPM samples are big-endian
+            header = `P6\n${width} ${height}\n65535\n`;
+            body = new Uint8Array(bytes.length);
+            for (let i = 0; i < bytes.length; i += 2) {
+                body[i] = bytes[i + 1];
+                body[i + 1] = bytes[i];
+            }
+            extension = 'ppm';
+        } else {
+            header = `P6\n${width} ${height}\n255\n`;
+            body = bytes;
+            extension = 'ppm';
+        }
 
-        // Increase zoom for next frame
-        this.zoom *= this.zoomSpeed;
+        const blob = new Blob([header, body], { type: 'application/octet-stream' });
+        const link = document.createElement('a');
+        link.href = URL.createObjectURL(blob);
+        link.download = `frame-${frame.frame_id}.${extension}`;
+        link.click();
+        URL.revokeObjectURL(link.href);
+    }
+
+    layerStack() {
+        // Empty means the server's default: palette outside, interior mode inside
+        if (this.layers === 'none') {
+            return [];
+        }
+        const layers = [
+            { source: { type: 'smooth' } },
+            { source: { type: 'interior', mode: this.interior } }
+        ];
+        layers.push({ source: { type: 'distance_estimate' }, blend: 'multiply' });
+        if (this.layers === 'trapped') {
+            layers.push({
+                source: { type: 'orbit_trap', shape: 'cross', scale: 4 },
+                blend: 'overlay',
+                opacity: 0.6,
+                palette: 'ocean'
+            });
+        }
+        return layers;
+    }
+
+    handleFormulaError(error) {
+        // Point at the offending part of the formula and stop until it's fixed
+        const source = this.customFormula;
+        const marker = ' '.repeat(error.start) + '^'.repeat(Math.max(1, error.end - error.start));
+        this.formulaErrorDisplay.textContent = `${error.message}\n${source}\n${marker}`;
+        this.stop();
     }
 
     handleStatus(status) {
@@ -259,12 +581,46 @@ class FractalZoomer {
         requestAnimationFrame(() => this.renderLoop());
     }
 
+    formulaSequence() {
+        // Hybrids are written as "formula:iterations,formula:iterations"
+        return this.formula.split(',').map(step => {
+            const [formula, iterations] = step.split(':');
+            return { formula, iterations: parseInt(iterations) || 1 };
+        });
+    }
+
+    paletteRequest() {
+        if (this.palette !== 'custom') {
+            return this.palette;
+        }
+        // Custom gradients are written as evenly spaced "#rrggbb" colours
+        const colours = this.gradient.split(',').map(c => c.trim()).filter(c => c);
+        return {
+            stops: colours.map((hex, i) => ({
+                position: i / colours.length,
+                colour: [1, 3, 5].map(o => parseInt(hex.substr(o, 2), 16) || 0)
+            })),
+            interpolation: 'linear',
+            space: this.gradientSpace
+        };
+    }
+
+    cancelFrame() {
+        // The coordinator answers with frame_cancelled, and the render loop
+        // then requests a fresh frame; a frame that already finished just arrives.
+        // Before the frame is accepted its id is unknown, so every frame is cancelled.
+        if (this.pendingFrame && this.socket && this.socket.readyState === WebSocket.OPEN) {
+            this.socket.send(JSON.stringify({ type: 'cancel_frame', frame_id: this.frameId }));
+        }
+    }
+
     requestFrame() {
         if (!this.socket || this.socket.readyState !== WebSocket.OPEN) {
             return;
         }
 
         this.pendingFrame = true;
+        this.frameId = null;
 
         // Scale max iterations with zoom level
         const scaledIterations = Math.min(
@@ -280,9 +636,33 @@ class FractalZoomer {
             center_y: this.centerY,
             zoom: this.zoom,
             max_iterations: scaledIterations,
-            palette: this.palette,
-            colour_interior: this.colourInterior
+            palette: this.paletteRequest(),
+            interior: this.interior,
+            interior_colour: [1, 3, 5].map(o => parseInt(this.interiorColour.substr(o, 2), 16)),
+            progressive: this.progressive,
+            reproject: this.reproject,
+            lighting: this.lighting === 'none' ? null : {
+                model: this.lighting,
+                angle: this.lightAngle
+            },
+            projection: this.projection,
+            formula: this.formulaSequence(),
+            custom_formula: this.customFormula || null,
+            bailout: {
+                radius: this.bailoutRadius,
+                norm: this.bailoutNorm
+            },
+            colouring: this.colouring,
+            pixel_format: this.pixelFormat,
+            dither: this.dither,
+            colour_mapping: {
+                transfer: this.transfer,
+                density: this.density,
+                offset: this.colourOffset
+            },
+            layers: this.layerStack()
         };
+        this.colourOffset = (this.colourOffset + this.colourCycle) % 1;
 
         this.socket.send(JSON.stringify(request));
 